@@ -1,9 +1,13 @@
 
 extern crate idl_poc;
 extern crate time;
+extern crate crossbeam_utils;
 
 use idl_poc::{IDLSimple, IDLBitRange};
+use idl_poc::cowcell::CowCell;
 use std::iter::FromIterator;
+use std::sync::{Arc, Mutex};
+use crossbeam_utils::thread::scope;
 
 // Trying to make these work with trait bounds is literally too hard
 // So just make our own impls.
@@ -71,6 +75,247 @@ fn bench_range_intersection(id: &str, a: Vec<u64>, b: Vec<u64>) {
     println!("range inter  {}: {} -> {}", id, end - start, result);
 }
 
+#[cfg(feature = "rayon")]
+fn bench_range_par_intersection(id: &str, a: Vec<u64>, b: Vec<u64>) {
+    let idl_a = IDLBitRange::from_iter(a);
+    let idl_b = IDLBitRange::from_iter(b);
+
+    let start = time::now();
+    let idl_result = idl_a.intersection(&idl_b);
+    let result = range_consume_results(&idl_result);
+    let end = time::now();
+    println!("range inter  {}: {} -> {}", id, end - start, result);
+
+    let start = time::now();
+    let idl_result = idl_a.par_intersection(&idl_b);
+    let result = range_consume_results(&idl_result);
+    let end = time::now();
+    println!("range par inter {}: {} -> {}", id, end - start, result);
+}
+
+fn bench_range_nth(id: &str, a: Vec<u64>, n: usize) {
+    let idl_a = IDLBitRange::from_iter(a);
+
+    // Walk bit by bit, as the default nth would.
+    let start = time::now();
+    let mut iter = (&idl_a).into_iter();
+    for _ in 0..n {
+        iter.next();
+    }
+    let result = iter.next();
+    let end = time::now();
+    println!("range walk {}: {} -> {:?}", id, end - start, result);
+
+    let start = time::now();
+    let result = (&idl_a).into_iter().nth(n);
+    let end = time::now();
+    println!("range nth  {}: {} -> {:?}", id, end - start, result);
+}
+
+fn bench_simple_skewed_intersection(id: &str, a: Vec<u64>, b: Vec<u64>) {
+    let idl_a = IDLSimple::from_iter(a);
+    let idl_b = IDLSimple::from_iter(b);
+
+    // A plain linear merge, as intersection did before it searched.
+    let start = time::now();
+    let mut result = 0;
+    let mut liter = (&idl_a).into_iter().peekable();
+    let mut riter = (&idl_b).into_iter().peekable();
+    while let (Some(l), Some(r)) = (liter.peek().cloned(), riter.peek().cloned()) {
+        if l == r {
+            result += l;
+            liter.next();
+            riter.next();
+        } else if l < r {
+            liter.next();
+        } else {
+            riter.next();
+        }
+    }
+    let end = time::now();
+    println!("simple merge  {}: {} -> {}", id, end - start, result);
+
+    let start = time::now();
+    let idl_result = idl_a.intersection(&idl_b);
+    let result = simple_consume_results(&idl_result);
+    let end = time::now();
+    println!("simple search {}: {} -> {}", id, end - start, result);
+}
+
+fn bench_range_skewed_intersection(id: &str, a: Vec<u64>, b: Vec<u64>) {
+    let idl_a = IDLBitRange::from_iter(a);
+    let idl_b = IDLBitRange::from_iter(b);
+
+    // Step over the ranges one at a time, as intersection did before it
+    // galloped. Both arms build the result set and sum it before stopping
+    // the clock, so they do the same work.
+    let start = time::now();
+    let mut idl_result = IDLBitRange::default();
+    let mut liter = idl_a.ranges().peekable();
+    let mut riter = idl_b.ranges().peekable();
+    while let (Some(l), Some(r)) = (liter.peek().cloned(), riter.peek().cloned()) {
+        if l.0 == r.0 {
+            idl_result.push_range(l.0, l.1 & r.1).unwrap();
+            liter.next();
+            riter.next();
+        } else if l.0 < r.0 {
+            liter.next();
+        } else {
+            riter.next();
+        }
+    }
+    let result = range_consume_results(&idl_result);
+    let end = time::now();
+    println!("range step   {}: {} -> {}", id, end - start, result);
+
+    let start = time::now();
+    let idl_result = idl_a.intersection(&idl_b);
+    let result = range_consume_results(&idl_result);
+    let end = time::now();
+    println!("range gallop {}: {} -> {}", id, end - start, result);
+}
+
+fn bench_range_from_sorted_slice(id: &str, a: Vec<u64>) {
+    let start = time::now();
+    let idl_a = IDLBitRange::from_iter(a.iter().cloned());
+    let end = time::now();
+    println!("range from_iter  {}: {} -> {}", id, end - start, range_consume_results(&idl_a));
+
+    let start = time::now();
+    let idl_a = IDLBitRange::from_sorted_slice(&a);
+    let end = time::now();
+    println!("range from_slice {}: {} -> {}", id, end - start, range_consume_results(&idl_a));
+}
+
+fn bench_range_union_into(id: &str, a: Vec<u64>, b: Vec<u64>) {
+    let idl_a = IDLBitRange::from_iter(a);
+    let idl_b = IDLBitRange::from_iter(b);
+
+    let start = time::now();
+    let idl_result = idl_a.union(&idl_b);
+    let end = time::now();
+    println!("range union      {}: {} -> {}", id, end - start, range_consume_results(&idl_result));
+
+    // Merge the smaller set into the larger one's list.
+    let (mut idl_large, idl_small) = if idl_a.count_ranges() >= idl_b.count_ranges() {
+        (idl_a, idl_b)
+    } else {
+        (idl_b, idl_a)
+    };
+    let start = time::now();
+    idl_large.union_into(&idl_small);
+    let end = time::now();
+    println!("range union_into {}: {} -> {}", id, end - start, range_consume_results(&idl_large));
+}
+
+// Merge the ranges of a and b onto the end of result, as union does.
+fn union_by_push(a: &IDLBitRange, b: &IDLBitRange, result: &mut IDLBitRange) {
+    let mut liter = a.ranges().peekable();
+    let mut riter = b.ranges().peekable();
+    loop {
+        let (range, mask) = match (liter.peek().cloned(), riter.peek().cloned()) {
+            (Some(l), Some(r)) if l.0 == r.0 => {
+                liter.next();
+                riter.next();
+                (l.0, l.1 | r.1)
+            }
+            (Some(l), Some(r)) if l.0 < r.0 => {
+                liter.next();
+                l
+            }
+            (_, Some(r)) => {
+                riter.next();
+                r
+            }
+            (Some(l), None) => {
+                liter.next();
+                l
+            }
+            (None, None) => break,
+        };
+        result.push_range(range, mask).unwrap();
+    }
+}
+
+// Compare building a union result with and without reserving up front.
+fn bench_range_union_reserve(id: &str, a: Vec<u64>, b: Vec<u64>) {
+    let idl_a = IDLBitRange::from_iter(a);
+    let idl_b = IDLBitRange::from_iter(b);
+
+    // Growing from empty, as union did before it reserved.
+    let start = time::now();
+    let mut idl_result = IDLBitRange::default();
+    union_by_push(&idl_a, &idl_b, &mut idl_result);
+    let end = time::now();
+    println!("range union grow    {}: {} -> {}", id, end - start, range_consume_results(&idl_result));
+
+    let start = time::now();
+    let mut idl_result = IDLBitRange::default();
+    idl_result.reserve(idl_a.count_ranges().max(idl_b.count_ranges()));
+    union_by_push(&idl_a, &idl_b, &mut idl_result);
+    let end = time::now();
+    println!("range union reserve {}: {} -> {}", id, end - start, range_consume_results(&idl_result));
+
+    let start = time::now();
+    let idl_result = idl_a.union(&idl_b);
+    let end = time::now();
+    println!("range union         {}: {} -> {}", id, end - start, range_consume_results(&idl_result));
+}
+
+fn bench_range_intersects(id: &str, a: Vec<u64>, b: Vec<u64>) {
+    let idl_a = IDLBitRange::from_iter(a);
+    let idl_b = IDLBitRange::from_iter(b);
+
+    // Build the intersection, then check it, as callers did before.
+    let start = time::now();
+    let idl_result = idl_a.intersection(&idl_b);
+    let result = range_consume_results(&idl_result) > 0;
+    let end = time::now();
+    println!("range and-check  {}: {} -> {}", id, end - start, result);
+
+    let start = time::now();
+    let result = idl_a.intersects(&idl_b);
+    let end = time::now();
+    println!("range intersects {}: {} -> {}", id, end - start, result);
+}
+
+// Compare read txns against the Mutex<Arc<_>> the CowCell used to hold.
+fn bench_cowcell_read(id: &str, threads: usize, reads: usize) {
+    let active = Mutex::new(Arc::new(0_i64));
+
+    let start = time::now();
+    scope(|scope| {
+        let active_ref = &active;
+        for _ in 0..threads {
+            scope.spawn(move || {
+                for _ in 0..reads {
+                    let txn = active_ref.lock().unwrap().clone();
+                    assert!(*txn >= 0);
+                }
+            });
+        }
+    });
+    let end = time::now();
+    println!("mutex arc read {}: {}", id, end - start);
+
+    let cc = CowCell::new(0_i64);
+
+    let start = time::now();
+    scope(|scope| {
+        let cc_ref = &cc;
+        for _ in 0..threads {
+            scope.spawn(move || {
+                for _ in 0..reads {
+                    let txn = cc_ref.begin_read_txn();
+                    assert!(**txn >= 0);
+                }
+            });
+        }
+    });
+    let end = time::now();
+    println!("cowcell read   {}: {}", id, end - start);
+}
+
 fn test_duplex(id: &str, a: Vec<u64>, b: Vec<u64>) {
     bench_simple_intersection(id, a.clone(), b.clone());
     bench_range_intersection(id, a.clone(), b.clone());
@@ -210,6 +455,28 @@ fn test_complex(id: &str, a: Vec<u64>, b: Vec<u64>, c: Vec<u64>) {
     bench_c_range(id, a.clone(), b.clone(), c.clone());
 }
 
+fn bench_range_union_all(id: &str, sets: Vec<Vec<u64>>) {
+    let idls: Vec<IDLBitRange> = sets.iter()
+                                     .map(|x| IDLBitRange::from_iter(x.clone()))
+                                     .collect();
+
+    let start = time::now();
+    let idl_result = idls.into_iter().fold(IDLBitRange::from_iter(Vec::<u64>::new()), |acc, x| acc | x);
+    let result = range_consume_results(&idl_result);
+    let end = time::now();
+    println!("range n  union {}: {} -> {}", id, end - start, result);
+
+    let idls: Vec<IDLBitRange> = sets.into_iter()
+                                     .map(IDLBitRange::from_iter)
+                                     .collect();
+
+    let start = time::now();
+    let idl_result = IDLBitRange::union_all(idls);
+    let result = range_consume_results(&idl_result);
+    let end = time::now();
+    println!("range union_all {}: {} -> {}", id, end - start, result);
+}
+
 fn main() {
     test_duplex(
         "1",
@@ -398,6 +665,75 @@ fn main() {
         vec3,
     );
 
+    let sets: Vec<Vec<u64>> = (0..32)
+        .map(|i| Vec::from_iter((i * 1024)..(i * 1024 + 8192)))
+        .collect();
+    bench_range_union_all("n: 1", sets);
+    let sets: Vec<Vec<u64>> = (0..32)
+        .map(|i| (1..300).map(|j| 64 * j * (i + 1) + 5).collect())
+        .collect();
+    bench_range_union_all("n: 2", sets);
+    println!("=====");
+
+    bench_range_nth("nth: 1", Vec::from_iter(1..1024000), 1000000);
+
+    bench_range_union_into(
+        "2",
+        vec![1],
+        Vec::from_iter(1..102400)
+    );
+
+    bench_range_from_sorted_slice("build: 1", Vec::from_iter(1..204800));
+
+    bench_range_union_reserve(
+        "1",
+        (0..1024000).map(|i| i * 64).collect(),
+        (0..1024000).map(|i| i * 64 + 32).collect(),
+    );
+
+    bench_range_intersects(
+        "1",
+        (0..1024000).map(|i| i * 2).collect(),
+        (0..1024000).map(|i| i * 2 + 1).collect(),
+    );
+    bench_range_intersects(
+        "2",
+        Vec::from_iter(1..1024000),
+        Vec::from_iter(512000..2048000),
+    );
+
+    bench_range_skewed_intersection(
+        "disjoint: 1",
+        Vec::from_iter(1..1024000),
+        Vec::from_iter(2048000..3072000),
+    );
+    bench_range_skewed_intersection(
+        "skew: 2",
+        vec![5000 * 64 + 8, 90000 * 64 + 16],
+        (0..100000).map(|i| i * 64 + (i % 64)).collect(),
+    );
+
+    bench_simple_skewed_intersection(
+        "skew: 1",
+        vec![2, 3, 35, 64, 128, 150, 152, 180, 256, 900, 1024, 1500, 1600, 2400, 40000, 78900],
+        Vec::from_iter(1..1024000),
+    );
+
+    bench_simple_skewed_intersection(
+        "skew: 2",
+        (0..2000).map(|i| i * 487 + 3).collect(),
+        Vec::from_iter(1..1024000),
+    );
+
+    bench_cowcell_read("cc: 1", 8, 100000);
+
+    #[cfg(feature = "rayon")]
+    bench_range_par_intersection(
+        "par: 1",
+        Vec::from_iter(1..20480000),
+        Vec::from_iter(10240000..30720000),
+    );
+
     test_complex(
         "comp: 1",
         Vec::from_iter(1..102400),
@@ -1,102 +1,458 @@
-
 use crossbeam_epoch as epoch;
 use crossbeam_epoch::{Atomic, Owned, Shared, Guard};
 use std::ptr;
-use std::sync::atomic::Ordering::{Release, Acquire};
+use std::array;
+use std::sync::atomic::Ordering::{Release, Acquire, Relaxed};
 use std::sync::{Mutex, MutexGuard};
 use std::collections::LinkedList;
+use std::marker::PhantomData;
+use std::iter::FromIterator;
 
+// The default node capacity. A leaf holds up to N pairs, and a branch up
+// to N keys and N + 1 links. Bst<K, V> uses this through the default on
+// its N parameter, which stands in for a type alias.
 const CAPACITY: usize = 5;
-const L_CAPACITY: usize = CAPACITY + 1;
 
-struct Bst<K, V> {
+/// A concurrently readable ordered map, built as a B+tree.
+///
+/// Reads see the tree as of the last commit, and never block or are
+/// blocked by the single writer. Old txns are freed from an epoch deferred
+/// closure, which can run on any thread once readers have left, so keys
+/// and values must be Send and own all their data.
+pub struct Bst<K, V, const N: usize = CAPACITY> where
+    K: Send + 'static,
+    V: Send + 'static,
+{
     write: Mutex<()>,
     // Shared root txn?
-    active: Atomic<BstTxn<K, V>>,
+    active: Atomic<BstTxn<K, V, N>>,
 }
 
-// Does bsttxn impl copy?
-struct BstTxn<K, V> {
+// Each write txn works on its own copy of the tree, so a txn owns every
+// node reachable from root, and frees them when dropped.
+struct BstTxn<K, V, const N: usize> {
     tid: u64,
-    root: *mut BstNode<K, V>,
+    root: *mut BstNode<K, V, N>,
     length: usize,
     // Contains garbage lists?
     // How can we set the garbage list of the former that we
     // copy from? Unsafe mut on the active? Mutex on the garbage list?
     // Cell of sometype?
-    owned: LinkedList<*mut BstNode<K, V>>,
+    owned: LinkedList<*mut BstNode<K, V, N>>,
 }
 
-struct BstWriteTxn<'a, K: 'a, V: 'a> {
-    txn: BstTxn<K, V>,
-    caller: &'a Bst<K, V>,
+/// A write txn of a `Bst`. Changes are only seen by readers once it is
+/// committed, and are dropped if it goes out of scope first.
+pub struct BstWriteTxn<'a, K, V, const N: usize> where
+    K: Send + 'static,
+    V: Send + 'static,
+{
+    txn: BstTxn<K, V, N>,
+    caller: &'a Bst<K, V, N>,
     _mguard: MutexGuard<'a, ()>
 }
 
-struct BstReadTxn<K, V> {
-    txn: *const BstTxn<K, V>,
+/// A read txn of a `Bst`, seeing the tree as of the last commit before it
+/// began.
+pub struct BstReadTxn<K, V, const N: usize> {
+    txn: *const BstTxn<K, V, N>,
     _guard: Guard,
 }
 
-struct BstLeaf<K, V> {
+/// Walks the pairs of a tree in key order.
+// The stack holds the branches above the current leaf, and the link of
+// each we descended through.
+pub struct BstIter<'a, K: 'a, V: 'a, const N: usize> {
+    stack: Vec<(*const BstBranch<K, V, N>, usize)>,
+    leaf: *const BstLeaf<K, V, N>,
+    idx: usize,
+    _marker: PhantomData<&'a BstNode<K, V, N>>,
+}
+
+/// Walks the pairs of a tree with keys in [lo, hi], in key order.
+pub struct BstRangeIter<'a, K: 'a, V: 'a, const N: usize> {
+    iter: BstIter<'a, K, V, N>,
+    hi: K,
+}
+
+struct BstLeaf<K, V, const N: usize> {
     /* These options get null pointer optimised for us :D */
-    key: [Option<K>; CAPACITY],
-    value: [Option<V>; CAPACITY],
-    parent: *mut BstNode<K, V>,
+    key: [Option<K>; N],
+    value: [Option<V>; N],
+    parent: *mut BstNode<K, V, N>,
     parent_idx: u16,
     capacity: u16,
     tid: u64,
 }
 
-struct BstBranch<K, V> {
-    key: [Option<K>; CAPACITY],
-    links: [*mut BstNode<K, V>; L_CAPACITY],
-    parent: *mut BstNode<K, V>,
+// links[i] holds the keys less than key[i], and links[i + 1] the keys
+// greater than or equal to it. Values are only stored in leaves. Stable
+// can't size an array as N + 1, so link N is kept apart in last; use
+// link and set_link rather than indexing links.
+struct BstBranch<K, V, const N: usize> {
+    key: [Option<K>; N],
+    links: [*mut BstNode<K, V, N>; N],
+    last: *mut BstNode<K, V, N>,
+    parent: *mut BstNode<K, V, N>,
     parent_idx: u16,
     capacity: u16,
     tid: u64,
 }
 
 // Do I even need an error type?
-enum BstErr {
+#[derive(Debug)]
+pub enum BstErr {
     Unknown,
 }
 
-enum BstNode<K, V> {
+// The outcome of an insert to a node.
+enum BstInsert<K, V, const N: usize> {
+    // The key was not present.
+    Inserted,
+    // The key was present, and this was its value.
+    Replaced(V),
+    // The key was not present, and the node overflowed. It was split, and
+    // the new node holds the keys >= the split key. The caller must link
+    // it in.
+    Split(K, *mut BstNode<K, V, N>),
+}
+
+enum BstNode<K, V, const N: usize> {
     Leaf {
-        inner: BstLeaf<K, V>
+        inner: BstLeaf<K, V, N>
     },
     Branch {
-        inner: BstBranch<K, V>
+        inner: BstBranch<K, V, N>
+    }
+}
+
+// Find key within the populated slots of a node.
+#[inline(always)]
+fn key_search<K: Ord>(keys: &[Option<K>], capacity: u16, key: &K) -> Result<usize, usize> {
+    keys[..capacity as usize].binary_search_by(|k| k.as_ref().unwrap().cmp(key))
+}
+
+// The link of a branch to follow for key.
+#[inline(always)]
+fn link_idx<K: Ord>(keys: &[Option<K>], capacity: u16, key: &K) -> usize {
+    match key_search(keys, capacity, key) {
+        Ok(i) => i + 1,
+        Err(i) => i,
+    }
+}
+
+impl<K, V, const N: usize> BstLeaf<K, V, N> where
+    K: Ord + Clone,
+{
+    // Below this many pairs a non-root leaf is rebalanced with a sibling.
+    const MIN: usize = N / 2;
+
+    fn empty(tid: u64) -> Self {
+        BstLeaf {
+            key: array::from_fn(|_| None),
+            value: array::from_fn(|_| None),
+            parent: ptr::null_mut(),
+            parent_idx: 0,
+            capacity: 0,
+            tid,
+        }
+    }
+
+    fn insert(&mut self, key: K, value: V) -> BstInsert<K, V, N> {
+        let cap = self.capacity as usize;
+        match key_search(&self.key, self.capacity, &key) {
+            Ok(i) => BstInsert::Replaced(self.value[i].replace(value).unwrap()),
+            Err(i) if cap < N => {
+                // Shift the greater keys up to open a slot.
+                for j in (i..cap).rev() {
+                    self.key[j + 1] = self.key[j].take();
+                    self.value[j + 1] = self.value[j].take();
+                }
+                self.key[i] = Some(key);
+                self.value[i] = Some(value);
+                self.capacity += 1;
+                BstInsert::Inserted
+            }
+            Err(i) => {
+                // We are full, so move the upper keys to a new leaf, picking
+                // the split so both halves are even once key is added.
+                let mid = N.div_ceil(2);
+                let split = if i < mid { mid - 1 } else { mid };
+                let mut right = BstLeaf::empty(self.tid);
+                right.capacity = (cap - split) as u16;
+                for j in split..cap {
+                    right.key[j - split] = self.key[j].take();
+                    right.value[j - split] = self.value[j].take();
+                }
+                self.capacity = split as u16;
+                if i < mid {
+                    self.insert(key, value);
+                } else {
+                    right.insert(key, value);
+                }
+                let split_key = right.key[0].clone().unwrap();
+                let right = Box::new(BstNode::Leaf { inner: right });
+                BstInsert::Split(split_key, Box::into_raw(right))
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> Option<(K, V)> {
+        let cap = self.capacity as usize;
+        let i = key_search(&self.key, self.capacity, key).ok()?;
+        let k = self.key[i].take().unwrap();
+        let v = self.value[i].take().unwrap();
+        // Close the gap.
+        for j in i..(cap - 1) {
+            self.key[j] = self.key[j + 1].take();
+            self.value[j] = self.value[j + 1].take();
+        }
+        self.capacity -= 1;
+        Some((k, v))
+    }
+
+    // Take all our pairs out, leaving us empty.
+    fn drain(&mut self) -> (Vec<K>, Vec<V>) {
+        let cap = self.capacity as usize;
+        self.capacity = 0;
+        (
+            self.key[..cap].iter_mut().map(|k| k.take().unwrap()).collect(),
+            self.value[..cap].iter_mut().map(|v| v.take().unwrap()).collect(),
+        )
+    }
+
+    fn fill(&mut self, keys: Vec<K>, values: Vec<V>) {
+        self.capacity = keys.len() as u16;
+        for (i, (k, v)) in keys.into_iter().zip(values).enumerate() {
+            self.key[i] = Some(k);
+            self.value[i] = Some(v);
+        }
+    }
+}
+
+impl<K, V, const N: usize> BstBranch<K, V, N> {
+    #[inline(always)]
+    fn link(&self, i: usize) -> *mut BstNode<K, V, N> {
+        if i < N { self.links[i] } else { self.last }
+    }
+
+    #[inline(always)]
+    fn set_link(&mut self, i: usize, link: *mut BstNode<K, V, N>) {
+        if i < N { self.links[i] = link } else { self.last = link }
     }
 }
 
-impl <K, V> BstNode<K, V> where
-    K: Clone + PartialEq,
+impl<K, V, const N: usize> BstBranch<K, V, N> where
+    K: Ord + Clone,
+    V: Clone,
+{
+    // The most keys a branch can hold.
+    const MAX: usize = N;
+    // Below this many keys a non-root branch is rebalanced with a sibling.
+    const MIN: usize = N / 2;
+
+    fn empty(tid: u64) -> Self {
+        BstBranch {
+            key: array::from_fn(|_| None),
+            links: [ptr::null_mut(); N],
+            last: ptr::null_mut(),
+            parent: ptr::null_mut(),
+            parent_idx: 0,
+            capacity: 0,
+            tid,
+        }
+    }
+
+    // Point our children back at us, after links have moved.
+    fn fix_children(&mut self, self_ptr: *mut BstNode<K, V, N>) {
+        for i in 0..(self.capacity as usize + 1) {
+            unsafe {
+                (*self.link(i)).set_parent(self_ptr, i as u16);
+            }
+        }
+    }
+
+    fn insert(&mut self, self_ptr: *mut BstNode<K, V, N>, key: K, value: V) -> BstInsert<K, V, N> {
+        let idx = link_idx(&self.key, self.capacity, &key);
+        match unsafe { (*self.link(idx)).insert(key, value) } {
+            BstInsert::Split(split_key, right) => self.insert_link(self_ptr, idx, split_key, right),
+            r => r,
+        }
+    }
+
+    // Add the node split from links[idx], splitting ourself if needed.
+    fn insert_link(&mut self, self_ptr: *mut BstNode<K, V, N>, idx: usize, split_key: K, right: *mut BstNode<K, V, N>) -> BstInsert<K, V, N> {
+        let cap = self.capacity as usize;
+        if cap < Self::MAX {
+            for j in (idx..cap).rev() {
+                self.key[j + 1] = self.key[j].take();
+                self.set_link(j + 2, self.link(j + 1));
+            }
+            self.key[idx] = Some(split_key);
+            self.set_link(idx + 1, right);
+            self.capacity += 1;
+            self.fix_children(self_ptr);
+            return BstInsert::Inserted;
+        }
+
+        // We are full. Gather everything, keep the lower half, and push the
+        // middle key up to our parent as the split point for the new node.
+        let (mut keys, mut links) = self.drain();
+        keys.insert(idx, split_key);
+        links.insert(idx + 1, right);
+
+        let mid = N / 2;
+        let right_keys = keys.split_off(mid + 1);
+        let right_links = links.split_off(mid + 1);
+        let up_key = keys.pop().unwrap();
+
+        self.fill(self_ptr, keys, links);
+
+        let new_ptr = Box::into_raw(Box::new(BstNode::Branch { inner: BstBranch::empty(self.tid) }));
+        unsafe {
+            if let BstNode::Branch { ref mut inner } = *new_ptr {
+                inner.fill(new_ptr, right_keys, right_links);
+            }
+        }
+        BstInsert::Split(up_key, new_ptr)
+    }
+
+    fn remove(&mut self, self_ptr: *mut BstNode<K, V, N>, key: &K) -> Option<(K, V)> {
+        let idx = link_idx(&self.key, self.capacity, key);
+        let r = unsafe { (*self.link(idx)).remove(key) };
+        if r.is_some() && unsafe { (*self.link(idx)).is_underflow() } {
+            self.rebalance(self_ptr, idx);
+        }
+        r
+    }
+
+    // links[idx] is too small, so either take some of a sibling's keys, or
+    // merge the two if they fit in one node.
+    fn rebalance(&mut self, self_ptr: *mut BstNode<K, V, N>, idx: usize) {
+        let l = if idx > 0 { idx - 1 } else { idx };
+        let left_ptr = self.link(l);
+        let right_ptr = self.link(l + 1);
+        let merged = unsafe {
+            match (&mut *left_ptr, &mut *right_ptr) {
+                (BstNode::Leaf { inner: left }, BstNode::Leaf { inner: right }) => {
+                    let (mut keys, mut values) = left.drain();
+                    let (rkeys, rvalues) = right.drain();
+                    keys.extend(rkeys);
+                    values.extend(rvalues);
+                    if keys.len() <= N {
+                        left.fill(keys, values);
+                        true
+                    } else {
+                        let mid = keys.len() / 2;
+                        let rkeys = keys.split_off(mid);
+                        let rvalues = values.split_off(mid);
+                        self.key[l] = Some(rkeys[0].clone());
+                        left.fill(keys, values);
+                        right.fill(rkeys, rvalues);
+                        false
+                    }
+                }
+                (BstNode::Branch { inner: left }, BstNode::Branch { inner: right }) => {
+                    // The separator comes down between the two sets of keys.
+                    let (mut keys, mut links) = left.drain();
+                    keys.push(self.key[l].take().unwrap());
+                    let (rkeys, rlinks) = right.drain();
+                    keys.extend(rkeys);
+                    links.extend(rlinks);
+                    if keys.len() <= Self::MAX {
+                        left.fill(left_ptr, keys, links);
+                        true
+                    } else {
+                        let mid = keys.len() / 2;
+                        let rkeys = keys.split_off(mid + 1);
+                        let rlinks = links.split_off(mid + 1);
+                        self.key[l] = keys.pop();
+                        left.fill(left_ptr, keys, links);
+                        right.fill(right_ptr, rkeys, rlinks);
+                        false
+                    }
+                }
+                _ => unreachable!("siblings must be at the same depth"),
+            }
+        };
+        if merged {
+            // The right node is now empty, so unlink and free it. Its
+            // children (if any) were moved to the left node.
+            self.remove_link(self_ptr, l);
+            unsafe {
+                drop(Box::from_raw(right_ptr));
+            }
+        }
+    }
+
+    // Remove key[idx] and links[idx + 1], shifting the rest down.
+    fn remove_link(&mut self, self_ptr: *mut BstNode<K, V, N>, idx: usize) {
+        let cap = self.capacity as usize;
+        for j in idx..(cap - 1) {
+            self.key[j] = self.key[j + 1].take();
+        }
+        self.key[cap - 1] = None;
+        for j in (idx + 1)..cap {
+            self.set_link(j, self.link(j + 1));
+        }
+        self.set_link(cap, ptr::null_mut());
+        self.capacity -= 1;
+        self.fix_children(self_ptr);
+    }
+
+    // Take all our keys and links out, leaving us empty.
+    fn drain(&mut self) -> (Vec<K>, Vec<*mut BstNode<K, V, N>>) {
+        let cap = self.capacity as usize;
+        let keys = self.key[..cap].iter_mut().map(|k| k.take().unwrap()).collect();
+        let links = (0..(cap + 1)).map(|i| self.link(i)).collect();
+        self.links = [ptr::null_mut(); N];
+        self.last = ptr::null_mut();
+        self.capacity = 0;
+        (keys, links)
+    }
+
+    fn fill(&mut self, self_ptr: *mut BstNode<K, V, N>, keys: Vec<K>, links: Vec<*mut BstNode<K, V, N>>) {
+        self.capacity = keys.len() as u16;
+        for (i, k) in keys.into_iter().enumerate() {
+            self.key[i] = Some(k);
+        }
+        for (i, l) in links.into_iter().enumerate() {
+            self.set_link(i, l);
+        }
+        self.fix_children(self_ptr);
+    }
+}
+
+impl<K, V, const N: usize> BstNode<K, V, N> where
+    K: Ord + Clone,
     V: Clone,
 {
     pub fn new_leaf(tid: u64) -> Self {
         BstNode::Leaf {
-            inner: BstLeaf {
-                key: [None, None, None, None, None],
-                value: [None, None, None, None, None],
-                parent:  ptr::null_mut(),
-                parent_idx: 0,
-                capacity: 0,
-                tid: tid
-            }
+            inner: BstLeaf::empty(tid)
         }
     }
 
-    fn new_branch(key: K, left: *mut BstNode<K, V>, right: *mut BstNode<K, V>, tid: u64) -> Self {
+    fn new_branch(key: K, left: *mut BstNode<K, V, N>, right: *mut BstNode<K, V, N>, tid: u64) -> Self {
+        let mut inner = BstBranch::empty(tid);
+        inner.key[0] = Some(key);
+        inner.set_link(0, left);
+        inner.set_link(1, right);
+        inner.capacity = 1;
         BstNode::Branch {
-            inner: BstBranch {
-                key: [Some(key), None, None, None, None],
-                links: [left, right, ptr::null_mut(), ptr::null_mut(), ptr::null_mut(), ptr::null_mut()],
-                parent: ptr::null_mut(),
-                parent_idx: 0,
-                capacity: 1,
-                tid: tid
+            inner
+        }
+    }
+
+    fn set_parent(&mut self, parent: *mut BstNode<K, V, N>, parent_idx: u16) {
+        match self {
+            BstNode::Leaf { inner } => {
+                inner.parent = parent;
+                inner.parent_idx = parent_idx;
+            }
+            BstNode::Branch { inner } => {
+                inner.parent = parent;
+                inner.parent_idx = parent_idx;
             }
         }
     }
@@ -104,46 +460,296 @@ impl <K, V> BstNode<K, V> where
     // Recurse and search.
     pub fn search(&self, key: &K) -> Option<&V> {
         match self {
-            &BstNode::Leaf { ref inner } => {
-                None
+            BstNode::Leaf { inner } => {
+                key_search(&inner.key, inner.capacity, key)
+                    .ok()
+                    .and_then(|i| inner.value[i].as_ref())
             }
-            &BstNode::Branch { ref inner } => {
-                None
+            BstNode::Branch { inner } => {
+                let idx = link_idx(&inner.key, inner.capacity, key);
+                unsafe {
+                    (*inner.link(idx)).search(key)
+                }
             }
         }
     }
 
-    pub fn insert(&mut self, key: K, value: V) -> Result<*mut BstNode<K, V>, BstErr> {
-        /* Should we auto split? */
-        Ok(ptr::null_mut())
-    }
-
-    pub fn update(&mut self, key: K, value: V) {
-        /* If not present, insert */
-        /* If present, replace */
+    pub fn insert(&mut self, key: K, value: V) -> BstInsert<K, V, N> {
+        /* Splits are returned for our parent to link in */
+        let self_ptr = self as *mut BstNode<K, V, N>;
+        match self {
+            BstNode::Leaf { inner } => inner.insert(key, value),
+            BstNode::Branch { inner } => inner.insert(self_ptr, key, value),
+        }
     }
 
     // Should this be a reference?
     pub fn remove(&mut self, key: &K) -> Option<(K, V)> {
         /* If present, remove */
         /* Else nothing, no-op */
-        None
+        /* Underflowed children are fixed by their parent */
+        let self_ptr = self as *mut BstNode<K, V, N>;
+        match self {
+            BstNode::Leaf { inner } => inner.remove(key),
+            BstNode::Branch { inner } => inner.remove(self_ptr, key),
+        }
+    }
+
+    // Follow the leftmost links down to the smallest key.
+    fn min_key(&self) -> Option<&K> {
+        match self {
+            BstNode::Leaf { inner } => inner.key[0].as_ref(),
+            BstNode::Branch { inner } => unsafe { (*inner.link(0)).min_key() },
+        }
+    }
+
+    // Follow the rightmost links down to the largest key.
+    fn max_key(&self) -> Option<&K> {
+        match self {
+            BstNode::Leaf { inner } => {
+                let cap = inner.capacity as usize;
+                if cap == 0 {
+                    None
+                } else {
+                    inner.key[cap - 1].as_ref()
+                }
+            }
+            BstNode::Branch { inner } => unsafe {
+                (*inner.link(inner.capacity as usize)).max_key()
+            },
+        }
+    }
+
+    fn is_underflow(&self) -> bool {
+        match self {
+            BstNode::Leaf { inner } => (inner.capacity as usize) < BstLeaf::<K, V, N>::MIN,
+            BstNode::Branch { inner } => (inner.capacity as usize) < BstBranch::<K, V, N>::MIN,
+        }
     }
 
     /* Return if the node is valid */
-    fn verify() -> bool {
-        false
+    fn verify(&self) -> bool {
+        self.verify_bounds(None, None).is_some()
+    }
+
+    // Check the node and all below it hold keys in [lo, hi), returning the
+    // depth of the leaves, which must all be the same.
+    fn verify_bounds(&self, lo: Option<&K>, hi: Option<&K>) -> Option<usize> {
+        let self_ptr = self as *const BstNode<K, V, N>;
+        let (keys, cap, max) = match self {
+            BstNode::Leaf { inner } => (&inner.key, inner.capacity as usize, N),
+            BstNode::Branch { inner } => (&inner.key, inner.capacity as usize, BstBranch::<K, V, N>::MAX),
+        };
+        // Capacity is exactly the populated slots.
+        if cap > max
+            || keys[..cap].iter().any(|k| k.is_none())
+            || keys[cap..].iter().any(|k| k.is_some())
+        {
+            return None;
+        }
+        let keys: Vec<&K> = keys[..cap].iter().map(|k| k.as_ref().unwrap()).collect();
+        if keys.windows(2).any(|w| w[0] >= w[1]) {
+            return None;
+        }
+        if let (Some(lo), Some(first)) = (lo, keys.first()) {
+            if *first < lo {
+                return None;
+            }
+        }
+        if let (Some(hi), Some(last)) = (hi, keys.last()) {
+            if *last >= hi {
+                return None;
+            }
+        }
+
+        match self {
+            BstNode::Leaf { inner } => {
+                if inner.value[..cap].iter().any(|v| v.is_none())
+                    || inner.value[cap..].iter().any(|v| v.is_some())
+                {
+                    return None;
+                }
+                Some(0)
+            }
+            BstNode::Branch { inner } => {
+                if cap == 0
+                    || (0..(cap + 1)).any(|i| inner.link(i).is_null())
+                    || ((cap + 1)..(N + 1)).any(|i| !inner.link(i).is_null())
+                {
+                    return None;
+                }
+                let mut depth = None;
+                for i in 0..(cap + 1) {
+                    let child = unsafe { &*inner.link(i) };
+                    let (parent, parent_idx) = match child {
+                        BstNode::Leaf { inner } => (inner.parent, inner.parent_idx),
+                        BstNode::Branch { inner } => (inner.parent, inner.parent_idx),
+                    };
+                    if !ptr::eq(parent, self_ptr) || parent_idx as usize != i {
+                        return None;
+                    }
+                    let clo = if i == 0 { lo } else { Some(keys[i - 1]) };
+                    let chi = if i == cap { hi } else { Some(keys[i]) };
+                    let d = child.verify_bounds(clo, chi)?;
+                    if depth.is_some() && depth != Some(d) {
+                        return None;
+                    }
+                    depth = Some(d);
+                }
+                depth.map(|d| d + 1)
+            }
+        }
     }
 
     fn map_nodes() -> () {
     }
+
+    // Copy this node and everything below it for a new txn.
+    fn clone_tree(&self, tid: u64) -> *mut BstNode<K, V, N> {
+        match self {
+            BstNode::Leaf { inner } => {
+                let mut new_leaf = BstLeaf::empty(tid);
+                new_leaf.key = inner.key.clone();
+                new_leaf.value = inner.value.clone();
+                new_leaf.capacity = inner.capacity;
+                Box::into_raw(Box::new(BstNode::Leaf { inner: new_leaf }))
+            }
+            BstNode::Branch { inner } => {
+                let mut new_branch = BstBranch::empty(tid);
+                new_branch.key = inner.key.clone();
+                new_branch.capacity = inner.capacity;
+                for i in 0..(inner.capacity as usize + 1) {
+                    let child = unsafe { (*inner.link(i)).clone_tree(tid) };
+                    new_branch.set_link(i, child);
+                }
+                let new_ptr = Box::into_raw(Box::new(BstNode::Branch { inner: new_branch }));
+                unsafe {
+                    if let BstNode::Branch { ref mut inner } = *new_ptr {
+                        inner.fix_children(new_ptr);
+                    }
+                }
+                new_ptr
+            }
+        }
+    }
 }
 
-impl<K, V> Bst<K, V> where
-    K: Clone + PartialEq,
-    V: Clone,
+// Free a node and everything below it.
+unsafe fn free_tree<K, V, const N: usize>(node: *mut BstNode<K, V, N>) {
+    let node = Box::from_raw(node);
+    if let BstNode::Branch { ref inner } = *node {
+        for i in 0..(inner.capacity as usize + 1) {
+            free_tree(inner.link(i));
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a, const N: usize> BstIter<'a, K, V, N> {
+    fn new(root: *const BstNode<K, V, N>) -> Self {
+        let mut iter = BstIter {
+            stack: Vec::new(),
+            leaf: ptr::null(),
+            idx: 0,
+            _marker: PhantomData,
+        };
+        iter.descend(root);
+        iter
+    }
+
+    // Follow the leftmost links from node down to a leaf.
+    fn descend(&mut self, mut node: *const BstNode<K, V, N>) {
+        loop {
+            match unsafe { &*node } {
+                BstNode::Leaf { inner } => {
+                    self.leaf = inner;
+                    self.idx = 0;
+                    return;
+                }
+                BstNode::Branch { inner } => {
+                    self.stack.push((inner, 0));
+                    node = inner.link(0);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K: 'a + Ord, V: 'a, const N: usize> BstIter<'a, K, V, N> {
+    // Position the iterator at the first key >= lo.
+    fn seek(root: *const BstNode<K, V, N>, lo: &K) -> Self {
+        let mut iter = BstIter {
+            stack: Vec::new(),
+            leaf: ptr::null(),
+            idx: 0,
+            _marker: PhantomData,
+        };
+        let mut node = root;
+        loop {
+            match unsafe { &*node } {
+                BstNode::Leaf { inner } => {
+                    iter.leaf = inner;
+                    // If every key here is < lo, next moves on to the
+                    // following leaf for us.
+                    iter.idx = match key_search(&inner.key, inner.capacity, lo) {
+                        Ok(i) => i,
+                        Err(i) => i,
+                    };
+                    return iter;
+                }
+                BstNode::Branch { inner } => {
+                    let idx = link_idx(&inner.key, inner.capacity, lo);
+                    iter.stack.push((inner, idx));
+                    node = inner.link(idx);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a, const N: usize> Iterator for BstIter<'a, K, V, N> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let leaf: &'a BstLeaf<K, V, N> = unsafe { &*self.leaf };
+            if self.idx < leaf.capacity as usize {
+                let i = self.idx;
+                self.idx += 1;
+                return Some((leaf.key[i].as_ref().unwrap(), leaf.value[i].as_ref().unwrap()));
+            }
+            // This leaf is done, so back up to the first branch with a
+            // link to the right of where we came from.
+            loop {
+                let (branch, i) = self.stack.pop()?;
+                let branch = unsafe { &*branch };
+                if i < branch.capacity as usize {
+                    self.stack.push((branch, i + 1));
+                    self.descend(branch.link(i + 1));
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K: 'a + Ord, V: 'a, const N: usize> Iterator for BstRangeIter<'a, K, V, N> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.iter.next() {
+            Some((k, v)) if *k <= self.hi => Some((k, v)),
+            _ => None,
+        }
+    }
+}
+
+impl<K, V, const N: usize> Bst<K, V, N> where
+    K: Ord + Clone + Send + 'static,
+    V: Clone + Send + 'static,
 {
     pub fn new() -> Self {
+        // Smaller nodes can't be split into two valid halves.
+        assert!(N >= 3, "Bst node capacity must be at least 3");
         let new_root = Box::new(
             BstNode::new_leaf(0)
         );
@@ -162,11 +768,24 @@ impl<K, V> Bst<K, V> where
         }
     }
 
-    fn commit(&self, new_txn: BstTxn<K, V>) -> Result<(), BstErr> {
+    fn commit(&self, new_txn: BstTxn<K, V, N>) -> Result<(), BstErr> {
+        let guard = epoch::pin();
+
+        let prev_txn = self.active.swap(Owned::new(new_txn), Release, &guard);
+        // Readers may still be in the old tree, so free it once they leave.
+        unsafe {
+            guard.defer(move || {
+                drop(prev_txn.into_owned());
+            });
+        }
         Ok(())
     }
 
-    pub fn begin_write_txn(&self) -> BstWriteTxn<K, V> {
+    /// Begin a write txn. This copies the whole tree up front, so every
+    /// write txn costs O(n) however little it changes. Nodes link to their
+    /// parent, so they can't be shared between txns to copy only the path a
+    /// write touches. Batch changes into one txn where possible.
+    pub fn begin_write_txn(&self) -> BstWriteTxn<K, V, N> {
         let mguard = self.write.lock().unwrap();
         let guard = epoch::pin();
 
@@ -182,7 +801,7 @@ impl<K, V> Bst<K, V> where
         }
     }
 
-    pub fn begin_read_txn(&self) -> BstReadTxn<K, V> {
+    pub fn begin_read_txn(&self) -> BstReadTxn<K, V, N> {
         let guard = epoch::pin();
 
         let cur = {
@@ -198,69 +817,214 @@ impl<K, V> Bst<K, V> where
 
 }
 
-impl<K, V> BstTxn<K, V> where
-    K: Clone + PartialEq,
+impl<K, V, const N: usize> Default for Bst<K, V, N> where
+    K: Ord + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    fn default() -> Self {
+        Bst::new()
+    }
+}
+
+impl<K, V, const N: usize> FromIterator<(K, V)> for Bst<K, V, N> where
+    K: Ord + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut bst = Bst::new();
+        bst.extend(iter);
+        bst
+    }
+}
+
+impl<K, V, const N: usize> Extend<(K, V)> for Bst<K, V, N> where
+    K: Ord + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    // All pairs are added in a single write txn. As with insert, a later
+    // value for a key replaces an earlier one.
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let mut wrtxn = self.begin_write_txn();
+        for (k, v) in iter {
+            wrtxn.insert(k, v).unwrap();
+        }
+        wrtxn.commit().unwrap();
+    }
+}
+
+// The raw node pointers stop these being derived, but every node is owned
+// by exactly one txn, only the write txn can change its tree, and old txns
+// are only freed once the epoch shows no reader can still see them.
+unsafe impl<K: Send + Sync + 'static, V: Send + Sync + 'static, const N: usize> Send for Bst<K, V, N> {}
+unsafe impl<K: Send + Sync + 'static, V: Send + Sync + 'static, const N: usize> Sync for Bst<K, V, N> {}
+
+impl<K, V, const N: usize> Drop for Bst<K, V, N> where
+    K: Send + 'static,
+    V: Send + 'static,
+{
+    fn drop(&mut self) {
+        // Read txns pin the epoch rather than borrow us, so they can still
+        // be in the active txn. Free it once they leave, as commit does.
+        let guard = epoch::pin();
+
+        let cur = self.active.load(Relaxed, &guard);
+        unsafe {
+            guard.defer(move || {
+                drop(cur.into_owned());
+            });
+        }
+    }
+}
+
+impl<K, V, const N: usize> BstTxn<K, V, N> where
+    K: Ord + Clone,
     V: Clone,
 {
     #[inline(always)]
     fn search(&self, key: &K) -> Option<&V> {
-        None
+        unsafe {
+            (*self.root).search(key)
+        }
+    }
+
+    #[inline(always)]
+    fn contains_key(&self, key: &K) -> bool {
+        self.search(key).is_some()
+    }
+
+    // Check the tree structure, and that length matches it.
+    fn verify(&self) -> bool {
+        let root = unsafe { &*self.root };
+        let root_parent = match root {
+            BstNode::Leaf { inner } => inner.parent,
+            BstNode::Branch { inner } => inner.parent,
+        };
+        root_parent.is_null() && root.verify() && self.iter().count() == self.length
+    }
+
+    #[inline(always)]
+    fn min_key(&self) -> Option<&K> {
+        unsafe {
+            (*self.root).min_key()
+        }
+    }
+
+    #[inline(always)]
+    fn max_key(&self) -> Option<&K> {
+        unsafe {
+            (*self.root).max_key()
+        }
     }
 
     #[inline(always)]
     fn insert(&mut self, key: K, value: V) -> Result<(), BstErr> {
+        self.update(key, value);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn update(&mut self, key: K, value: V) -> Option<V> {
+        /* If not present, insert */
+        /* If present, replace */
         /* Recursively insert. */
         /* This is probably an unsafe .... */
         unsafe {
-            (*self.root).insert(key, value).and_then(|nr: *mut _ | {
-                self.length += 1;
-                self.root = nr;
-                Ok(())
-            })
+            match (*self.root).insert(key, value) {
+                BstInsert::Inserted => {}
+                BstInsert::Replaced(v) => return Some(v),
+                BstInsert::Split(split_key, right) => {
+                    // The root split, so the tree grows a level.
+                    let new_root = Box::into_raw(Box::new(
+                        BstNode::new_branch(split_key, self.root, right, self.tid)
+                    ));
+                    if let BstNode::Branch { ref mut inner } = *new_root {
+                        inner.fix_children(new_root);
+                    }
+                    self.root = new_root;
+                }
+            }
         }
+        self.length += 1;
+        None
     }
 
     #[inline(always)]
     fn clear(&mut self) {
-        // Because this changes root from Some to None, it moves ownership
-        // of root to this function, and all it's descendants that are dropped.
-
-        /* !!!!!!!!!!!!!!!!!! TAKE OWNERSHIP OF ROOT AND FREE IT !!!!!!!!!!!!!!!!! */
-
-        // With EBR you need to walk the tree and mark everything to be dropped.
-        // Perhaps just the root needs EBR marking?
+        // We own every node of our tree, so free it all now.
         let new_root = Box::new(
             BstNode::new_leaf(self.tid)
         );
+        unsafe {
+            free_tree(self.root);
+        }
         self.root = Box::into_raw(new_root);
         self.length = 0;
     }
 
     #[inline(always)]
     fn remove(&mut self, key: &K) -> Option<(K, V)> {
-        None
+        let r = unsafe { (*self.root).remove(key) };
+        if r.is_some() {
+            self.length -= 1;
+        }
+        // If the root lost its last key, its only child becomes the root.
+        unsafe {
+            let old_root = self.root;
+            if let BstNode::Branch { ref inner } = *old_root {
+                if inner.capacity == 0 {
+                    self.root = inner.link(0);
+                    (*self.root).set_parent(ptr::null_mut(), 0);
+                    drop(Box::from_raw(old_root));
+                }
+            }
+        }
+        r
     }
 
     #[inline(always)]
     fn len(&self) -> usize {
         self.length
     }
+
+    #[inline(always)]
+    fn iter(&self) -> BstIter<'_, K, V, N> {
+        BstIter::new(self.root)
+    }
+
+    #[inline(always)]
+    fn range(&self, lo: &K, hi: &K) -> BstRangeIter<'_, K, V, N> {
+        BstRangeIter {
+            iter: BstIter::seek(self.root, lo),
+            hi: hi.clone(),
+        }
+    }
 }
 
-impl<K, V> Clone for BstTxn<K, V> {
+impl<K, V, const N: usize> Clone for BstTxn<K, V, N> where
+    K: Ord + Clone,
+    V: Clone,
+{
     fn clone(&self) -> Self {
         BstTxn {
             tid: self.tid + 1,
-            // Copies the root
-            root: self.root,
+            // Copies the tree, so our writes don't disturb readers.
+            root: unsafe { (*self.root).clone_tree(self.tid + 1) },
             length: self.length,
             owned: LinkedList::new(),
         }
     }
 }
 
-impl<K, V> BstReadTxn<K, V> where
-    K: Clone + PartialEq,
+impl<K, V, const N: usize> Drop for BstTxn<K, V, N> {
+    fn drop(&mut self) {
+        unsafe {
+            free_tree(self.root);
+        }
+    }
+}
+
+impl<K, V, const N: usize> BstReadTxn<K, V, N> where
+    K: Ord + Clone,
     V: Clone,
 {
     pub fn search(&self, key: &K) -> Option<&V> {
@@ -269,26 +1033,87 @@ impl<K, V> BstReadTxn<K, V> where
         }
     }
 
+    pub fn contains_key(&self, key: &K) -> bool {
+        unsafe {
+            (*self.txn).contains_key(key)
+        }
+    }
+
+    pub fn min_key(&self) -> Option<&K> {
+        unsafe {
+            (*self.txn).min_key()
+        }
+    }
+
+    pub fn max_key(&self) -> Option<&K> {
+        unsafe {
+            (*self.txn).max_key()
+        }
+    }
+
+    pub fn verify(&self) -> bool {
+        unsafe {
+            (*self.txn).verify()
+        }
+    }
+
     pub fn len(&self) -> usize {
         unsafe {
             (*self.txn).len()
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> BstIter<'_, K, V, N> {
+        unsafe {
+            (*self.txn).iter()
+        }
+    }
+
+    pub fn range(&self, lo: &K, hi: &K) -> BstRangeIter<'_, K, V, N> {
+        unsafe {
+            (*self.txn).range(lo, hi)
+        }
+    }
 }
 
 // This is really just a gateway wrapper to the bsttxn fns.
-impl<'a, K, V> BstWriteTxn<'a, K, V> where
-    K: Clone + PartialEq,
-    V: Clone,
+impl<'a, K, V, const N: usize> BstWriteTxn<'a, K, V, N> where
+    K: Ord + Clone + Send + 'static,
+    V: Clone + Send + 'static,
 {
     pub fn search(&self, key: &K) -> Option<&V> {
         self.txn.search(key)
     }
 
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.txn.contains_key(key)
+    }
+
+    pub fn min_key(&self) -> Option<&K> {
+        self.txn.min_key()
+    }
+
+    pub fn max_key(&self) -> Option<&K> {
+        self.txn.max_key()
+    }
+
+    pub fn verify(&self) -> bool {
+        self.txn.verify()
+    }
+
     pub fn insert(&mut self, key: K, value: V) -> Result<(), BstErr> {
         self.txn.insert(key, value)
     }
 
+    /// Insert or replace the value, returning the previous value if any.
+    pub fn update(&mut self, key: K, value: V) -> Option<V> {
+        self.txn.update(key, value)
+    }
+
     pub fn clear(&mut self) {
         self.txn.clear()
     }
@@ -302,6 +1127,18 @@ impl<'a, K, V> BstWriteTxn<'a, K, V> where
         self.txn.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> BstIter<'_, K, V, N> {
+        self.txn.iter()
+    }
+
+    pub fn range(&self, lo: &K, hi: &K) -> BstRangeIter<'_, K, V, N> {
+        self.txn.range(lo, hi)
+    }
+
     pub fn commit(mut self) -> Result<(), BstErr> {
         self.caller.commit(self.txn)
     }
@@ -311,7 +1148,8 @@ impl<'a, K, V> BstWriteTxn<'a, K, V> where
 
 #[cfg(test)]
 mod tests {
-    use super::Bst;
+    use super::{Bst, BstNode};
+    use crossbeam_utils::thread::scope;
     #[test]
     fn test_node_basic() {
         // Test that simple operations on nodes work as expected
@@ -323,7 +1161,7 @@ mod tests {
 
         // First, take a read_txn and check it's length.
         let rotxn_a = bst.begin_read_txn();
-        assert!(rotxn_a.len() == 0);
+        assert!(rotxn_a.is_empty());
         assert!(rotxn_a.search(&0) == None);
         assert!(rotxn_a.search(&1) == None);
 
@@ -339,7 +1177,7 @@ mod tests {
         }
 
         // original read should still show 0 len.
-        assert!(rotxn_a.len() == 0);
+        assert!(rotxn_a.is_empty());
         assert!(rotxn_a.search(&0) == None);
         assert!(rotxn_a.search(&1) == None);
         // New read should show 1 len.
@@ -349,4 +1187,439 @@ mod tests {
         assert!(rotxn_b.search(&1) == Some(&1));
         // Read txn goes out of scope here.
     }
+
+    fn insert_and_check(keys: &[i64]) {
+        let bst: Bst<i64, i64> = Bst::new();
+        let rotxn_a = bst.begin_read_txn();
+
+        {
+            let mut wrtxn = bst.begin_write_txn();
+            for k in keys {
+                wrtxn.insert(*k, *k * 2).unwrap();
+            }
+            assert!(wrtxn.len() == keys.len());
+            assert!(wrtxn.verify());
+            for k in keys {
+                assert!(wrtxn.search(k) == Some(&(*k * 2)));
+            }
+            // Inserting again replaces, and doesn't change the length.
+            wrtxn.insert(keys[0], 0).unwrap();
+            assert!(wrtxn.len() == keys.len());
+            assert!(wrtxn.search(&keys[0]) == Some(&0));
+            wrtxn.commit().unwrap();
+        }
+
+        let rotxn_b = bst.begin_read_txn();
+        assert!(rotxn_b.len() == keys.len());
+        assert!(rotxn_b.search(&-1).is_none());
+        assert!(rotxn_b.search(&(keys.len() as i64)).is_none());
+        for k in &keys[1..] {
+            assert!(rotxn_b.search(k) == Some(&(*k * 2)));
+        }
+        // We are well past CAPACITY, so the root must have split.
+        unsafe {
+            match *(*rotxn_b.txn).root {
+                BstNode::Branch { .. } => {}
+                BstNode::Leaf { .. } => panic!("root did not split"),
+            }
+        }
+
+        // The older read is untouched.
+        assert!(rotxn_a.is_empty());
+        assert!(rotxn_a.search(&keys[0]).is_none());
+    }
+
+    #[test]
+    fn test_insert_split_ascending() {
+        let keys: Vec<i64> = (0..200).collect();
+        insert_and_check(&keys);
+    }
+
+    #[test]
+    fn test_insert_split_descending() {
+        let keys: Vec<i64> = (0..200).rev().collect();
+        insert_and_check(&keys);
+    }
+
+    #[test]
+    fn test_insert_split_shuffled() {
+        // A fixed stride that is coprime to the length visits every key.
+        let keys: Vec<i64> = (0..211).map(|i| (i * 97) % 211).collect();
+        insert_and_check(&keys);
+    }
+
+    fn remove_and_check(removes: &[i64]) {
+        let bst: Bst<i64, i64> = Bst::new();
+        let count = removes.len() as i64;
+        {
+            let mut wrtxn = bst.begin_write_txn();
+            for k in 0..count {
+                wrtxn.insert(k, k * 2).unwrap();
+            }
+            wrtxn.commit().unwrap();
+        }
+        let rotxn_a = bst.begin_read_txn();
+
+        {
+            let mut wrtxn = bst.begin_write_txn();
+            // Absent keys are a no-op.
+            assert!(wrtxn.remove(&-1).is_none());
+            assert!(wrtxn.remove(&count).is_none());
+            for (n, k) in removes.iter().enumerate() {
+                assert!(wrtxn.remove(k) == Some((*k, *k * 2)));
+                assert!(wrtxn.remove(k).is_none());
+                assert!(wrtxn.len() == removes.len() - n - 1);
+                assert!(wrtxn.search(k).is_none());
+                assert!(wrtxn.verify());
+                // Check the survivors at a few points, rather than every step.
+                if n % 16 == 0 {
+                    for j in &removes[n + 1..] {
+                        assert!(wrtxn.search(j) == Some(&(*j * 2)));
+                    }
+                }
+                // Once few enough remain, merges collapse us to a single leaf.
+                if removes.len() - n - 1 <= 1 {
+                    unsafe {
+                        match *wrtxn.txn.root {
+                            BstNode::Leaf { .. } => {}
+                            BstNode::Branch { .. } => panic!("root did not merge"),
+                        }
+                    }
+                }
+            }
+            wrtxn.commit().unwrap();
+        }
+
+        let rotxn_b = bst.begin_read_txn();
+        assert!(rotxn_b.is_empty());
+        // The older read still sees everything.
+        assert!(rotxn_a.len() == removes.len());
+        for k in removes {
+            assert!(rotxn_a.search(k) == Some(&(*k * 2)));
+            assert!(rotxn_b.search(k).is_none());
+        }
+    }
+
+    #[test]
+    fn test_remove_ascending() {
+        let keys: Vec<i64> = (0..200).collect();
+        remove_and_check(&keys);
+    }
+
+    #[test]
+    fn test_remove_descending() {
+        let keys: Vec<i64> = (0..200).rev().collect();
+        remove_and_check(&keys);
+    }
+
+    #[test]
+    fn test_remove_shuffled() {
+        let keys: Vec<i64> = (0..211).map(|i| (i * 97) % 211).collect();
+        remove_and_check(&keys);
+    }
+
+    #[test]
+    fn test_remove_reinsert() {
+        // Interleave removes and inserts so borrows and merges happen
+        // against nodes that have already split.
+        let bst: Bst<i64, i64> = Bst::new();
+        let mut wrtxn = bst.begin_write_txn();
+        for k in 0..100 {
+            wrtxn.insert(k, k).unwrap();
+        }
+        for k in (0..100).filter(|k| k % 3 != 0) {
+            assert!(wrtxn.remove(&k) == Some((k, k)));
+        }
+        for k in (0..100).filter(|k| k % 3 == 1) {
+            wrtxn.insert(k, k).unwrap();
+        }
+        assert!(wrtxn.len() == 34 + 33);
+        assert!(wrtxn.verify());
+        for k in 0..100 {
+            if k % 3 == 2 {
+                assert!(wrtxn.search(&k).is_none());
+            } else {
+                assert!(wrtxn.search(&k) == Some(&k));
+            }
+        }
+    }
+
+    #[test]
+    fn test_iter() {
+        let bst: Bst<i64, i64> = Bst::new();
+        let rotxn_a = bst.begin_read_txn();
+        assert!(rotxn_a.iter().next().is_none());
+
+        {
+            let mut wrtxn = bst.begin_write_txn();
+            for k in (0..211).map(|i| (i * 97) % 211) {
+                wrtxn.insert(k, k * 2).unwrap();
+            }
+            let pairs: Vec<(i64, i64)> = wrtxn.iter().map(|(k, v)| (*k, *v)).collect();
+            let expect: Vec<(i64, i64)> = (0..211).map(|k| (k, k * 2)).collect();
+            assert!(pairs == expect);
+            wrtxn.commit().unwrap();
+        }
+
+        let rotxn_b = bst.begin_read_txn();
+        let keys: Vec<i64> = rotxn_b.iter().map(|(k, _)| *k).collect();
+        assert!(keys == (0..211).collect::<Vec<_>>());
+        assert!(rotxn_a.iter().next().is_none());
+    }
+
+    #[test]
+    fn test_read_txn_outlives_bst() {
+        let bst: Bst<i64, String> = Bst::new();
+        {
+            let mut wrtxn = bst.begin_write_txn();
+            for k in 0..64 {
+                wrtxn.insert(k, k.to_string()).unwrap();
+            }
+            wrtxn.commit().unwrap();
+        }
+        let rotxn = bst.begin_read_txn();
+        drop(bst);
+        // The read txn's pin must keep the active txn alive.
+        assert!(rotxn.search(&42) == Some(&"42".to_string()));
+        assert!(rotxn.iter().map(|(k, v)| (*k, v.clone())).eq((0..64).map(|k| (k, k.to_string()))));
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let bst: Bst<i64, i64> = Bst::new();
+        {
+            let mut wrtxn = bst.begin_write_txn();
+            for k in (0..100).map(|k| k * 2) {
+                wrtxn.insert(k, k).unwrap();
+            }
+            assert!(wrtxn.contains_key(&0));
+            assert!(!wrtxn.contains_key(&1));
+            wrtxn.commit().unwrap();
+        }
+        let rotxn = bst.begin_read_txn();
+        for k in 0..200 {
+            assert!(rotxn.contains_key(&k) == (k % 2 == 0));
+        }
+        assert!(!rotxn.contains_key(&-2));
+        assert!(!rotxn.contains_key(&200));
+    }
+
+    #[test]
+    fn test_range() {
+        let bst: Bst<i64, i64> = Bst::new();
+        {
+            let mut wrtxn = bst.begin_write_txn();
+            // Even keys only, so bounds can fall between keys.
+            for k in (0..150).map(|i| ((i * 97) % 150) * 2) {
+                wrtxn.insert(k, k).unwrap();
+            }
+            wrtxn.commit().unwrap();
+        }
+        let rotxn = bst.begin_read_txn();
+        let range = |lo: i64, hi: i64| -> Vec<i64> {
+            rotxn.range(&lo, &hi).map(|(k, _)| *k).collect()
+        };
+
+        // Bounds on keys, and between keys, mid node.
+        assert!(range(13, 27) == vec![14, 16, 18, 20, 22, 24, 26]);
+        assert!(range(14, 26) == vec![14, 16, 18, 20, 22, 24, 26]);
+        assert!(range(100, 100) == vec![100]);
+        // Empty ranges.
+        assert!(range(101, 101).is_empty());
+        assert!(range(30, 20).is_empty());
+        assert!(range(300, 400).is_empty());
+        assert!(range(-10, -1).is_empty());
+        // The whole tree.
+        assert!(range(0, 298) == (0..150).map(|k| k * 2).collect::<Vec<_>>());
+        assert!(range(-10, 1000) == (0..150).map(|k| k * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_from_iter_extend() {
+        let pairs: Vec<(i64, i64)> = (0..211).map(|i| ((i * 97) % 211, i)).collect();
+        let mut bst: Bst<i64, i64> = pairs.into_iter().collect();
+        {
+            let rotxn = bst.begin_read_txn();
+            assert!(rotxn.len() == 211);
+            let keys: Vec<i64> = rotxn.iter().map(|(k, _)| *k).collect();
+            assert!(keys == (0..211).collect::<Vec<_>>());
+        }
+
+        // Duplicate keys keep the last value, both against the tree and
+        // within the iterator.
+        bst.extend(vec![(5, -1), (300, 1), (300, 2)]);
+        let rotxn = bst.begin_read_txn();
+        assert!(rotxn.len() == 212);
+        assert!(rotxn.search(&5) == Some(&-1));
+        assert!(rotxn.search(&300) == Some(&2));
+    }
+
+    #[test]
+    fn test_update() {
+        let bst: Bst<i64, i64> = Bst::new();
+        let mut wrtxn = bst.begin_write_txn();
+        for k in 0..50 {
+            assert!(wrtxn.update(k, k).is_none());
+            assert!(wrtxn.len() == (k + 1) as usize);
+        }
+        // Existing keys hand back the old value, and keep the length.
+        assert!(wrtxn.update(10, 100) == Some(10));
+        assert!(wrtxn.update(10, 1000) == Some(100));
+        assert!(wrtxn.search(&10) == Some(&1000));
+        assert!(wrtxn.len() == 50);
+        // Absent keys are inserted.
+        assert!(wrtxn.update(-1, -1).is_none());
+        assert!(wrtxn.len() == 51);
+        assert!(wrtxn.search(&-1) == Some(&-1));
+    }
+
+    #[test]
+    fn test_min_max_key() {
+        let bst: Bst<i64, i64> = Bst::new();
+        let rotxn_a = bst.begin_read_txn();
+        assert!(rotxn_a.min_key().is_none());
+        assert!(rotxn_a.max_key().is_none());
+
+        let mut reference: Vec<i64> = (0..211).map(|i| ((i * 97) % 211) - 50).collect();
+        let mut wrtxn = bst.begin_write_txn();
+        for k in &reference {
+            wrtxn.insert(*k, *k).unwrap();
+        }
+        reference.sort();
+        assert!(wrtxn.min_key() == reference.first());
+        assert!(wrtxn.max_key() == reference.last());
+
+        // Still correct once the ends are removed.
+        wrtxn.remove(&-50);
+        wrtxn.remove(&160);
+        assert!(wrtxn.min_key() == Some(&-49));
+        assert!(wrtxn.max_key() == Some(&159));
+    }
+
+    #[test]
+    fn test_verify() {
+        let bst: Bst<i64, i64> = Bst::new();
+        let mut wrtxn = bst.begin_write_txn();
+        assert!(wrtxn.verify());
+        for k in 0..100 {
+            wrtxn.insert(k, k).unwrap();
+        }
+        assert!(wrtxn.verify());
+
+        // Swap two keys in the first leaf so it is out of order.
+        unsafe {
+            let mut node = wrtxn.txn.root;
+            while let BstNode::Branch { ref inner } = *node {
+                node = inner.link(0);
+            }
+            if let BstNode::Leaf { ref mut inner } = *node {
+                inner.key.swap(0, 1);
+                assert!(!wrtxn.verify());
+                inner.key.swap(0, 1);
+            }
+        }
+        assert!(wrtxn.verify());
+
+        // A length that disagrees with the tree.
+        wrtxn.txn.length += 1;
+        assert!(!wrtxn.verify());
+        wrtxn.txn.length -= 1;
+
+        // A broken parent link.
+        unsafe {
+            if let BstNode::Branch { ref inner } = *wrtxn.txn.root {
+                (*inner.link(1)).set_parent(inner.link(1), 1);
+            }
+        }
+        assert!(!wrtxn.verify());
+    }
+
+    fn capacity_check<const N: usize>() {
+        let bst: Bst<i64, i64, N> = Bst::new();
+        let keys: Vec<i64> = (0..1009).map(|i| (i * 97) % 1009).collect();
+        let mut wrtxn = bst.begin_write_txn();
+        for k in &keys {
+            wrtxn.insert(*k, *k).unwrap();
+        }
+        assert!(wrtxn.len() == keys.len());
+        assert!(wrtxn.verify());
+        assert!(wrtxn.iter().map(|(k, _)| *k).eq(0..1009));
+        for k in keys.iter().filter(|k| *k % 4 != 0) {
+            assert!(wrtxn.remove(k) == Some((*k, *k)));
+        }
+        assert!(wrtxn.verify());
+        assert!(wrtxn.iter().map(|(k, _)| *k).eq((0..1009).filter(|k| k % 4 == 0)));
+    }
+
+    #[test]
+    fn test_capacity() {
+        // The smallest node that works, and one with a wide fan-out.
+        capacity_check::<3>();
+        capacity_check::<4>();
+        capacity_check::<64>();
+    }
+
+    #[test]
+    fn test_branch_fanout() {
+        // A branch fills all N keys, and so N + 1 links, before it splits.
+        let bst: Bst<i64, i64> = Bst::new();
+        let mut wrtxn = bst.begin_write_txn();
+        let mut widest = 0;
+        for k in 0..200 {
+            wrtxn.insert(k, k).unwrap();
+            if let BstNode::Branch { ref inner } = unsafe { &*wrtxn.txn.root } {
+                widest = widest.max(inner.capacity);
+            }
+        }
+        assert!(widest == 5);
+        assert!(wrtxn.verify());
+    }
+
+    const MT_KEYS: usize = 300;
+
+    fn mt_writer(bst: &Bst<i64, i64>) {
+        for k in 0..(MT_KEYS as i64) {
+            let mut wrtxn = bst.begin_write_txn();
+            wrtxn.insert(k, k).unwrap();
+            wrtxn.commit().unwrap();
+        }
+    }
+
+    fn mt_reader(bst: &Bst<i64, i64>) {
+        let mut last_len = 0;
+        while last_len < MT_KEYS {
+            let rotxn = bst.begin_read_txn();
+            let len = rotxn.len();
+            assert!(len >= last_len);
+            last_len = len;
+            // We see exactly the keys of the last commit, and none of any
+            // write in progress.
+            assert!(rotxn.iter().map(|(k, _)| *k).eq(0..(len as i64)));
+            assert!(!rotxn.contains_key(&(len as i64)));
+            assert!(rotxn.verify());
+        }
+    }
+
+    #[test]
+    fn test_multithread_create() {
+        let bst: Bst<i64, i64> = Bst::new();
+
+        scope(|scope| {
+            let bst_ref = &bst;
+
+            let _readers: Vec<_> = (0..7).map(|_| {
+                scope.spawn(move || {
+                    mt_reader(bst_ref);
+                })
+            }).collect();
+
+            scope.spawn(move || {
+                mt_writer(bst_ref);
+            });
+        });
+
+        let rotxn = bst.begin_read_txn();
+        assert!(rotxn.len() == MT_KEYS);
+        assert!(rotxn.verify());
+    }
 }
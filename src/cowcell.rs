@@ -1,34 +1,114 @@
 
-use std::sync::{Mutex, MutexGuard, RwLock, Arc};
+use crossbeam_epoch as epoch;
+use crossbeam_epoch::{Atomic, Owned, Shared};
+use std::sync::{Mutex, MutexGuard, RwLock, Arc, TryLockError};
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct CowCellInner<T> {
     data: T,
+    generation: u64,
 }
 
 impl<T> CowCellInner<T> {
     pub fn new(data: T) -> Self {
+        CowCellInner::with_generation(data, 0)
+    }
+
+    fn with_generation(data: T, generation: u64) -> Self {
         CowCellInner {
-            data: data,
+            data,
+            generation,
         }
     }
-}
 
-type CowCellReadTxn<T> = Arc<CowCellInner<T>>;
+    /// The commit generation this value was installed at.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
 
+/// A read transaction, holding the value as of when it began alive and
+/// unchanged by later commits.
+///
+/// Cloning a read txn shares the same snapshot. This only bumps a
+/// refcount, and never touches the cell's locks.
 #[derive(Debug)]
-pub struct CowCell<T> {
+pub struct CowCellReadTxn<T> {
+    inner: Arc<CowCellInner<T>>,
+}
+
+impl<T> Clone for CowCellReadTxn<T> {
+    fn clone(&self) -> Self {
+        CowCellReadTxn {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Deref for CowCellReadTxn<T> {
+    type Target = CowCellInner<T>;
+
+    #[inline]
+    fn deref(&self) -> &CowCellInner<T> {
+        &self.inner
+    }
+}
+
+/// A stable snapshot of the cell's value, taken by `CowCell::get`. It
+/// derefs directly to `T`, and is unaffected by later commits.
+#[derive(Debug, Clone)]
+pub struct CowCellSnapshot<T> {
+    inner: Arc<CowCellInner<T>>,
+}
+
+type CowCellOnCommit<T> = Box<dyn Fn(&T) + Send + Sync>;
+
+// The active value is only ever replaced whole, so it is always valid even
+// if a thread panicked while holding a lock. We recover from poisoning
+// rather than leave the cell unusable.
+//
+// A replaced value is freed once all readers have unpinned, which may be on
+// any thread and at any later time, hence the bounds on T.
+pub struct CowCell<T>
+    where T: Send + Sync + 'static
+{
     write: Mutex<()>,
     // I suspect that Mutex is faster here due to lack of needing draining.
     // RWlock 500 MT: PT2.354443857S
     // Mutex 500 MT: PT0.006423466S
     // EBR 500 MT: PT0.003360303S
-    active: Mutex<CowCellReadTxn<T>>,
+    active: Atomic<Arc<CowCellInner<T>>>,
+    // Bumped on every commit, so readers can tell if anything changed.
+    generation: AtomicU64,
+    // Checked before on_commit so that commits skip the lock when no
+    // callback was ever set.
+    has_on_commit: AtomicBool,
+    on_commit: RwLock<Option<CowCellOnCommit<T>>>,
+    // Makes the working copy for a write txn.
+    cloner: fn(&T) -> T,
+}
+
+impl<T> fmt::Debug for CowCell<T>
+    where T: fmt::Debug + Send + Sync + 'static
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CowCell")
+            .field("write", &self.write)
+            .field("active", &*self.load_active())
+            .field("generation", &self.generation)
+            .finish()
+    }
 }
 
 #[derive(Debug)]
-pub struct CowCellWriteTxn<'a, T: 'a> {
+pub struct CowCellWriteTxn<'a, T>
+    where T: Send + Sync + 'static
+{
     // Hold open the guard, and initiate the copy to here.
     work: T,
     // This way we know who to contact for updating our data ....
@@ -38,32 +118,105 @@ pub struct CowCellWriteTxn<'a, T: 'a> {
 
 
 impl<T> CowCell<T>
-    where T: Clone
+    where T: Clone + Send + Sync + 'static
 {
     pub fn new(data: T) -> Self {
+        CowCell::new_with_cloner(data, T::clone)
+    }
+
+    /// Consume the cell, returning the last committed value. This is only
+    /// cloned if read txns still hold it.
+    pub fn into_inner(self) -> T {
+        // Leave null behind so our Drop has nothing to free.
+        let inner = unsafe {
+            let guard = epoch::unprotected();
+            *self.active.swap(Shared::null(), Ordering::AcqRel, guard).into_owned().into_box()
+        };
+        match Arc::try_unwrap(inner) {
+            Ok(inner) => inner.data,
+            Err(inner) => inner.data.clone(),
+        }
+    }
+}
+
+impl<T> CowCell<T>
+    where T: Send + Sync + 'static
+{
+    /// Create a cell whose write txns get their working copy from `cloner`
+    /// rather than `Clone`. This allows structural sharing, for example
+    /// copying only the outer parts of a value that holds an `Arc`, so long
+    /// as anything shared is never mutated in place.
+    pub fn new_with_cloner(data: T, cloner: fn(&T) -> T) -> Self {
         CowCell {
             write: Mutex::new(()),
-            active: Mutex::new(
+            active: Atomic::new(
                 Arc::new(
                     CowCellInner::new(data)
                 )
             ),
+            generation: AtomicU64::new(0),
+            has_on_commit: AtomicBool::new(false),
+            on_commit: RwLock::new(None),
+            cloner,
         }
     }
 
+    /// Set a callback to run with the new value after each commit. This
+    /// replaces any previous callback. It runs while the write lock is
+    /// still held, so should be quick, and must not write to this cell.
+    pub fn set_on_commit<F: Fn(&T) + Send + Sync + 'static>(&self, f: F) {
+        *self.on_commit.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(f));
+        self.has_on_commit.store(true, Ordering::Release);
+    }
+
+    /// The number of commits made to this cell.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
     pub fn begin_read_txn(&self) -> CowCellReadTxn<T> {
-        let rwguard = self.active.lock().unwrap();
-        rwguard.clone()
-        // rwguard ends here
+        CowCellReadTxn {
+            inner: self.load_active(),
+        }
+    }
+
+    /// Take a snapshot of the current value for a one-off read.
+    pub fn get(&self) -> CowCellSnapshot<T> {
+        CowCellSnapshot {
+            inner: self.begin_read_txn().inner,
+        }
     }
 
     pub fn begin_write_txn(&self) -> CowCellWriteTxn<T> {
         /* Take the exclusive write lock first */
-        let mguard = self.write.lock().unwrap();
+        let mguard = self.write.lock().unwrap_or_else(|e| e.into_inner());
+        self.write_txn_with_guard(mguard)
+    }
+
+    /// Begin a write txn, waiting at most `dur` for another writer to
+    /// finish. Returns `None` if the write lock could not be taken in time.
+    pub fn begin_write_txn_timeout(&self, dur: Duration) -> Option<CowCellWriteTxn<'_, T>> {
+        // std's Mutex has no timed lock, so poll with a short sleep.
+        let deadline = Instant::now() + dur;
+        loop {
+            match self.write.try_lock() {
+                Ok(mguard) => return Some(self.write_txn_with_guard(mguard)),
+                Err(TryLockError::Poisoned(e)) => return Some(self.write_txn_with_guard(e.into_inner())),
+                Err(TryLockError::WouldBlock) => {}
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(1)));
+        }
+    }
+
+    fn write_txn_with_guard<'a>(&'a self, mguard: MutexGuard<'a, ()>) -> CowCellWriteTxn<'a, T> {
         /* Now take a ro-txn to get the data copied */
-        let rwguard = self.active.lock().unwrap();
+        let inner = self.load_active();
         /* This copies the data */
-        let data: T = (***rwguard).clone();
+        let data: T = (self.cloner)(&**inner);
         /* Now build the write struct */
         CowCellWriteTxn {
             work: data,
@@ -72,11 +225,73 @@ impl<T> CowCell<T>
         }
     }
 
+    /// Install a new value. This serialises against other writers, but
+    /// skips copying the current value.
+    pub fn replace(&self, value: T) {
+        let _mguard = self.write.lock().unwrap_or_else(|e| e.into_inner());
+        self.commit(value);
+    }
+
+    /// Apply `f` to a copy of the latest value and commit the result.
+    pub fn update<F: FnOnce(&mut T)>(&self, f: F) {
+        let mut wrtxn = self.begin_write_txn();
+        f(wrtxn.get_mut());
+        wrtxn.commit();
+    }
+
     fn commit(&self, newdata: T) {
-        let mut rwguard = self.active.lock().unwrap();
-        let new_inner = Arc::new(CowCellInner::new(newdata));
-        // now over-write the last value in the mutex.
-        *rwguard = new_inner;
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        let new_inner = Arc::new(CowCellInner::with_generation(newdata, generation));
+        {
+            let guard = epoch::pin();
+            let prev = self.active.swap(Owned::new(new_inner.clone()), Ordering::AcqRel, &guard);
+            // A reader may still be cloning the old Arc, so only free our
+            // handle to it once they have all unpinned.
+            unsafe {
+                guard.defer(move || {
+                    drop(prev.into_owned());
+                });
+            }
+        }
+        // Readers can see the new value now, but other writers are still
+        // held off by our caller.
+        if self.has_on_commit.load(Ordering::Acquire) {
+            if let Some(ref f) = *self.on_commit.read().unwrap_or_else(|e| e.into_inner()) {
+                f(&new_inner.data);
+            }
+        }
+    }
+
+    fn load_active(&self) -> Arc<CowCellInner<T>> {
+        let guard = epoch::pin();
+        let cur = self.active.load(Ordering::Acquire, &guard);
+        // We always hold a value, except within into_inner.
+        unsafe { cur.deref() }.clone()
+    }
+}
+
+impl<T> Drop for CowCell<T>
+    where T: Send + Sync + 'static
+{
+    fn drop(&mut self) {
+        // We have exclusive access, and any readers hold their own Arc, so
+        // the active value can be freed directly.
+        unsafe {
+            let cur = self.active.load(Ordering::Relaxed, epoch::unprotected());
+            if !cur.is_null() {
+                drop(cur.into_owned());
+            }
+        }
+    }
+}
+
+/// Compares a snapshot of each cell's current value. The snapshots are
+/// taken one after the other, so this is not atomic across both cells.
+impl<T> PartialEq for CowCell<T>
+    where T: PartialEq + Send + Sync + 'static
+{
+    fn eq(&self, other: &Self) -> bool {
+        *self.get() == *other.get()
     }
 }
 
@@ -89,6 +304,15 @@ impl<T> Deref for CowCellInner<T> {
     }
 }
 
+impl<T> Deref for CowCellSnapshot<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.inner.data
+    }
+}
+
 impl<T> AsRef<T> for CowCellInner<T> {
     #[inline]
     fn as_ref(&self) -> &T {
@@ -97,7 +321,7 @@ impl<T> AsRef<T> for CowCellInner<T> {
 }
 
 impl<'a, T> CowCellWriteTxn<'a, T>
-    where T: Clone
+    where T: Send + Sync + 'static
 {
     /* commit */
     /* get_mut data */
@@ -110,6 +334,10 @@ impl<'a, T> CowCellWriteTxn<'a, T>
         /* Write our data back to the CowCell */
         self.caller.commit(self.work);
     }
+
+    /// Discard the working copy and release the write lock. This is the
+    /// same as dropping the txn without calling commit.
+    pub fn abort(self) {}
 }
 
 
@@ -119,6 +347,9 @@ mod tests {
 
     use std::sync::{Mutex, MutexGuard, RwLock, Arc};
     use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
     use super::CowCell;
     use crossbeam_utils::thread::scope;
 
@@ -155,6 +386,308 @@ mod tests {
         assert_eq!(**cc_rotxn_a, 0);
     }
 
+    #[test]
+    fn test_generation() {
+        let data: i64 = 0;
+        let cc = CowCell::new(data);
+        assert_eq!(cc.generation(), 0);
+
+        let cc_rotxn_a = cc.begin_read_txn();
+        assert_eq!(cc_rotxn_a.generation(), 0);
+        // Reads and aborts don't change the generation.
+        let cc_rotxn_b = cc.begin_read_txn();
+        assert_eq!(cc_rotxn_b.generation(), 0);
+        cc.begin_write_txn().abort();
+        assert_eq!(cc.generation(), 0);
+
+        for i in 1..5 {
+            cc.update(|v| *v += 1);
+            assert_eq!(cc.generation(), i);
+            assert_eq!(cc.begin_read_txn().generation(), i);
+        }
+        cc.replace(0);
+        assert_eq!(cc.generation(), 5);
+        assert_eq!(cc_rotxn_a.generation(), 0);
+    }
+
+    #[test]
+    fn test_read_txn_clone() {
+        let data: i64 = 0;
+        let cc = CowCell::new(data);
+
+        let cc_rotxn_b = {
+            let cc_rotxn_a = cc.begin_read_txn();
+            cc_rotxn_a.clone()
+        };
+        assert_eq!(**cc_rotxn_b, 0);
+
+        cc.update(|v| *v = 1);
+        let cc_rotxn_c = cc_rotxn_b.clone();
+        // Both clones outlive the original, and the commit.
+        assert_eq!(**cc_rotxn_b, 0);
+        assert_eq!(**cc_rotxn_c, 0);
+        assert_eq!(cc_rotxn_c.generation(), 0);
+        assert_eq!(**cc.begin_read_txn(), 1);
+    }
+
+    #[test]
+    fn test_write_txn_timeout() {
+        let data: i64 = 0;
+        let cc = CowCell::new(data);
+
+        // Uncontended, we get the txn straight away.
+        let cc_wrtxn = cc.begin_write_txn_timeout(Duration::from_millis(10));
+        assert!(cc_wrtxn.is_some());
+        drop(cc_wrtxn);
+
+        let (tx, rx) = mpsc::channel();
+        scope(|scope| {
+            let cc_ref = &cc;
+            scope.spawn(move || {
+                let mut cc_wrtxn = cc_ref.begin_write_txn();
+                tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(200));
+                *cc_wrtxn.get_mut() = 1;
+                cc_wrtxn.commit();
+            });
+
+            rx.recv().unwrap();
+            let start = Instant::now();
+            assert!(cc_ref.begin_write_txn_timeout(Duration::from_millis(20)).is_none());
+            assert!(start.elapsed() >= Duration::from_millis(20));
+            // Waiting long enough sees the other writer's commit.
+            let mut cc_wrtxn = cc_ref.begin_write_txn_timeout(Duration::from_secs(10)).unwrap();
+            assert_eq!(*cc_wrtxn.get_mut(), 1);
+        });
+    }
+
+    #[test]
+    fn test_on_commit() {
+        let data: i64 = 0;
+        let cc = CowCell::new(data);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        // Commits before the callback was set aren't seen.
+        cc.update(|v| *v = 1);
+        let seen_ref = seen.clone();
+        cc.set_on_commit(move |v| seen_ref.lock().unwrap().push(*v));
+
+        cc.update(|v| *v += 1);
+        cc.begin_write_txn().abort();
+        let mut cc_wrtxn = cc.begin_write_txn();
+        *cc_wrtxn.get_mut() = 10;
+        drop(cc_wrtxn);
+        cc.replace(5);
+        let mut cc_wrtxn = cc.begin_write_txn();
+        *cc_wrtxn.get_mut() += 1;
+        cc_wrtxn.commit();
+
+        assert_eq!(*seen.lock().unwrap(), vec![2, 5, 6]);
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let data: i64 = 0;
+        let cc = CowCell::new(data);
+        cc.update(|v| *v = 1);
+        cc.replace(2);
+        assert_eq!(cc.into_inner(), 2);
+
+        // An outstanding read txn still sees its own snapshot.
+        let cc = CowCell::new(vec![1, 2]);
+        cc.update(|v| v.push(3));
+        let cc_rotxn = cc.begin_read_txn();
+        let mut cc_wrtxn = cc.begin_write_txn();
+        cc_wrtxn.get_mut().push(4);
+        cc_wrtxn.abort();
+        assert_eq!(cc.into_inner(), vec![1, 2, 3]);
+        assert_eq!(**cc_rotxn, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_partial_eq() {
+        let cc_a = CowCell::new(vec![1, 2, 3]);
+        let cc_b = CowCell::new(vec![1, 2]);
+        assert!(cc_a != cc_b);
+        cc_b.update(|v| v.push(3));
+        assert!(cc_a == cc_b);
+        // Only the values matter, not how many commits made them.
+        assert!(cc_a.generation() != cc_b.generation());
+        assert!(cc_a == cc_a);
+    }
+
+    #[test]
+    fn test_poison_recovery() {
+        let data: i64 = 0;
+        let cc = CowCell::new(data);
+
+        scope(|scope| {
+            let cc_ref = &cc;
+            let h = scope.spawn(move || {
+                let mut cc_wrtxn = cc_ref.begin_write_txn();
+                *cc_wrtxn.get_mut() = 1;
+                panic!("writer failed");
+            });
+            assert!(h.join().is_err());
+        });
+        assert!(cc.write.is_poisoned());
+
+        // The failed write never committed, and the cell is still usable.
+        assert_eq!(*cc.get(), 0);
+        cc.update(|v| *v += 2);
+        assert_eq!(*cc.get(), 2);
+        assert!(cc.begin_write_txn_timeout(Duration::from_millis(10)).is_some());
+        cc.replace(3);
+        assert_eq!(cc.into_inner(), 3);
+    }
+
+    #[derive(Debug)]
+    struct TestShared {
+        big: Arc<Vec<i64>>,
+        version: i64,
+    }
+
+    // A deep clone, so the cloner is the only way to share.
+    impl Clone for TestShared {
+        fn clone(&self) -> Self {
+            TestShared {
+                big: Arc::new((*self.big).clone()),
+                version: self.version,
+            }
+        }
+    }
+
+    fn test_shared_cloner(v: &TestShared) -> TestShared {
+        TestShared {
+            big: v.big.clone(),
+            version: v.version,
+        }
+    }
+
+    #[test]
+    fn test_new_with_cloner() {
+        let cc = CowCell::new_with_cloner(
+            TestShared { big: Arc::new(vec![1, 2, 3]), version: 0 },
+            test_shared_cloner
+        );
+        let cc_rotxn_a = cc.begin_read_txn();
+
+        let mut cc_wrtxn = cc.begin_write_txn();
+        assert!(Arc::ptr_eq(&cc_wrtxn.get_mut().big, &cc_rotxn_a.big));
+        cc_wrtxn.get_mut().version = 1;
+        cc_wrtxn.commit();
+
+        let cc_rotxn_b = cc.begin_read_txn();
+        assert!(Arc::ptr_eq(&cc_rotxn_a.big, &cc_rotxn_b.big));
+        assert_eq!(cc_rotxn_a.version, 0);
+        assert_eq!(cc_rotxn_b.version, 1);
+
+        // Changing the shared part copies it, and isolates older readers.
+        cc.update(|v| Arc::make_mut(&mut v.big).push(4));
+        let cc_rotxn_c = cc.begin_read_txn();
+        assert!(!Arc::ptr_eq(&cc_rotxn_b.big, &cc_rotxn_c.big));
+        assert_eq!(*cc_rotxn_b.big, vec![1, 2, 3]);
+        assert_eq!(*cc_rotxn_c.big, vec![1, 2, 3, 4]);
+
+        // The default cell makes a deep copy.
+        let cc = CowCell::new(TestShared { big: Arc::new(vec![1]), version: 0 });
+        let cc_rotxn_a = cc.begin_read_txn();
+        let mut cc_wrtxn = cc.begin_write_txn();
+        assert!(!Arc::ptr_eq(&cc_wrtxn.get_mut().big, &cc_rotxn_a.big));
+    }
+
+    // Deliberately not Clone, so it can only be used through a cloner.
+    #[derive(Debug)]
+    struct TestNoClone {
+        big: Arc<Vec<i64>>,
+    }
+
+    #[test]
+    fn test_new_with_cloner_no_clone() {
+        let cc = CowCell::new_with_cloner(
+            TestNoClone { big: Arc::new(vec![1, 2, 3]) },
+            |v| TestNoClone { big: v.big.clone() }
+        );
+        let cc_rotxn_a = cc.begin_read_txn();
+        cc.update(|v| v.big = Arc::new(vec![4]));
+        let mut cc_wrtxn = cc.begin_write_txn();
+        cc_wrtxn.get_mut().big = Arc::new(vec![5]);
+        cc_wrtxn.commit();
+        cc.replace(TestNoClone { big: Arc::new(vec![6]) });
+
+        assert_eq!(*cc_rotxn_a.big, vec![1, 2, 3]);
+        assert_eq!(*cc.get().big, vec![6]);
+        assert_eq!(cc.generation(), 3);
+    }
+
+    #[test]
+    fn test_abort() {
+        let data: i64 = 0;
+        let cc = CowCell::new(data);
+
+        let mut cc_wrtxn = cc.begin_write_txn();
+        *cc_wrtxn.get_mut() = 1;
+        cc_wrtxn.abort();
+
+        let cc_rotxn = cc.begin_read_txn();
+        assert_eq!(**cc_rotxn, 0);
+        // The write lock was released.
+        cc.update(|v| *v = 2);
+        assert_eq!(*cc.get(), 2);
+    }
+
+    #[test]
+    fn test_get_snapshot() {
+        let data: i64 = 0;
+        let cc = CowCell::new(data);
+        let snap_a = cc.get();
+        assert_eq!(*snap_a, 0);
+
+        scope(|scope| {
+            let cc_ref = &cc;
+            scope.spawn(move || {
+                let mut cc_wrtxn = cc_ref.begin_write_txn();
+                *cc_wrtxn.get_mut() = 1;
+                cc_wrtxn.commit();
+            });
+        });
+
+        // The snapshot was taken before the commit and must not move.
+        assert_eq!(*snap_a, 0);
+        let snap_b = cc.get();
+        assert_eq!(*snap_b, 1);
+        assert_eq!(*snap_a, 0);
+    }
+
+    #[test]
+    fn test_replace_update() {
+        let data: i64 = 0;
+        let cc = CowCell::new(data);
+        let cc_rotxn_a = cc.begin_read_txn();
+
+        cc.replace(5);
+        assert_eq!(*cc.get(), 5);
+        cc.update(|v| {
+            // We start from the last committed value.
+            assert_eq!(*v, 5);
+            *v += 1;
+        });
+        assert_eq!(*cc.get(), 6);
+        assert_eq!(**cc_rotxn_a, 0);
+
+        scope(|scope| {
+            let cc_ref = &cc;
+            let _writers: Vec<_> = (0..4).map(|_| {
+                scope.spawn(move || {
+                    for _ in 0..100 {
+                        cc_ref.update(|v| *v += 1);
+                    }
+                })
+            }).collect();
+        });
+        assert_eq!(*cc.get(), 406);
+    }
+
     #[test]
     fn test_multithread_create() {
         let start = time::now();
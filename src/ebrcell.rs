@@ -4,6 +4,7 @@ use crossbeam_epoch::{Atomic, Owned, Guard};
 use std::sync::atomic::Ordering::{Relaxed, Release};
 
 use std::sync::{Mutex, MutexGuard};
+use std::fmt;
 use std::mem;
 use std::ops::Deref;
 
@@ -13,11 +14,13 @@ use std::ops::Deref;
 /// affecting current readers.
 ///
 /// Changes are only stored in the structure until you call commit: to
-/// abort a change, don't call commit and allow the write transaction to
-/// go out of scope. This causes the `EbrCell` to unlock allowing other
-/// writes to proceed.
+/// abort a change, call abort, or don't call commit and allow the write
+/// transaction to go out of scope. This causes the `EbrCell` to unlock
+/// allowing other writes to proceed.
 #[derive(Debug)]
-pub struct EbrCellWriteTxn<'a, T: 'a> {
+pub struct EbrCellWriteTxn<'a, T>
+    where T: Send + Sync + 'static
+{
     data: Option<T>,
     // This way we know who to contact for updating our data ....
     caller: &'a EbrCell<T>,
@@ -25,7 +28,7 @@ pub struct EbrCellWriteTxn<'a, T: 'a> {
 }
 
 impl<'a, T> EbrCellWriteTxn<'a, T>
-    where T: Clone
+    where T: Clone + Send + Sync + 'static
 {
     /// Access a mutable pointer of the data in the `EbrCell`. This data is only
     /// visible to this write transaction object in this thread until you call
@@ -45,6 +48,11 @@ impl<'a, T> EbrCellWriteTxn<'a, T>
         mem::swap(&mut element, &mut self.data);
         self.caller.commit(element);
     }
+
+    /// Discard the changes in this write transaction and unlock the
+    /// `EbrCell`. This is equivalent to dropping the transaction without
+    /// calling commit, but makes the intent explicit.
+    pub fn abort(self) {}
 }
 
 /// A concurrently readable cell.
@@ -66,6 +74,10 @@ impl<'a, T> EbrCellWriteTxn<'a, T>
 /// Writers are serialised and are guaranteed they have exclusive write access
 /// to the structure.
 ///
+/// Replaced data is freed from an epoch deferred drop, which may run on any
+/// thread and at any later time, so `T` must be `Send`, `Sync` and own all
+/// of its data.
+///
 /// # Examples
 /// ```
 /// use idl_poc::ebrcell::EbrCell;
@@ -93,13 +105,15 @@ impl<'a, T> EbrCellWriteTxn<'a, T>
 /// assert_eq!(*new_read_txn, 1);
 /// ```
 #[derive(Debug)]
-pub struct EbrCell<T> {
+pub struct EbrCell<T>
+    where T: Send + Sync + 'static
+{
     write: Mutex<()>,
     active: Atomic<T>,
 }
 
 impl<T> EbrCell<T>
-    where T: Clone
+    where T: Clone + Send + Sync + 'static
 {
     /// Create a new EbrCell storing type T. T must implement Clone.
     pub fn new(data: T) -> Self {
@@ -114,6 +128,26 @@ impl<T> EbrCell<T>
     pub fn begin_write_txn(&self) -> EbrCellWriteTxn<T> {
         /* Take the exclusive write lock first */
         let mguard = self.write.lock().unwrap();
+        self.write_txn_with_guard(mguard)
+    }
+
+    /// Attempt to begin a write transaction without blocking. If another
+    /// writer holds the `EbrCell` this returns `None`.
+    pub fn try_begin_write_txn(&self) -> Option<EbrCellWriteTxn<'_, T>> {
+        self.write.try_lock().ok().map(|mguard| self.write_txn_with_guard(mguard))
+    }
+
+    /// Apply `f` to a copy of the current data and commit the result. This
+    /// serialises with other writers, so no update is lost.
+    pub fn update<F: FnOnce(&mut T)>(&self, f: F) {
+        let mut wrtxn = self.begin_write_txn();
+        f(wrtxn.get_mut());
+        wrtxn.commit();
+    }
+
+    /// Copy the active data into a new write transaction. We only pin once
+    /// the write lock is held, and the pin is released before returning.
+    fn write_txn_with_guard<'a>(&'a self, mguard: MutexGuard<'a, ()>) -> EbrCellWriteTxn<'a, T> {
         /* Do an atomic load of the current value */
         let guard = epoch::pin();
         let cur_shared = self.active.load(Relaxed, &guard);
@@ -153,6 +187,14 @@ impl<T> EbrCell<T>
         // Then return the current data with a readtxn. Do we need a new guard scope?
     }
 
+    /// Advance the epoch and try to run deferred drops of old data, rather
+    /// than waiting for it to happen opportunistically. This is best effort:
+    /// data still pinned by a read transaction on any thread can't be freed.
+    pub fn flush(&self) {
+        let guard = epoch::pin();
+        guard.flush();
+    }
+
     /// Begin a read transaction. The returned [`EbrCellReadTxn'] guarantees
     /// the data lives long enough via crossbeam's Epoch type. When this is
     /// dropped the data *may* be freed at some point in the future.
@@ -174,7 +216,9 @@ impl<T> EbrCell<T>
     }
 }
 
-impl<T> Drop for EbrCell<T> {
+impl<T> Drop for EbrCell<T>
+    where T: Send + Sync + 'static
+{
     fn drop(&mut self) {
         // Right, we are dropping! Everything is okay here *except*
         // that we need to tell our active data to be unlinked, else it may
@@ -192,13 +236,52 @@ impl<T> Drop for EbrCell<T> {
 
 /// A read transaction. This stores a reference to the data from the main
 /// `EbrCell`, and guarantees it is alive for the duration of the read.
-// #[derive(Debug)]
-pub struct EbrCellReadTxn<T> {
+pub struct EbrCellReadTxn<T>
+    where T: Send + Sync + 'static
+{
     _guard: Guard,
     data: *const T,
 }
 
-impl<T> Deref for EbrCellReadTxn<T> {
+impl<T> fmt::Debug for EbrCellReadTxn<T>
+    where T: fmt::Debug + Send + Sync + 'static
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Our guard keeps the data alive, so deref is safe.
+        f.debug_struct("EbrCellReadTxn")
+            .field("data", &**self)
+            .finish()
+    }
+}
+
+impl<T> Clone for EbrCellReadTxn<T>
+    where T: Send + Sync + 'static
+{
+    /// Create a new read transaction on the same data, with its own pin.
+    /// Read transactions can't leave their thread, and pins on one thread
+    /// nest, so the clone's pin keeps the original's epoch and the data
+    /// stays alive for as long as either is held.
+    fn clone(&self) -> Self {
+        EbrCellReadTxn {
+            _guard: epoch::pin(),
+            data: self.data,
+        }
+    }
+}
+
+impl<T> EbrCellReadTxn<T>
+    where T: Clone + Send + Sync + 'static
+{
+    /// Clone the value out of the read transaction. Unlike the transaction
+    /// itself, the returned value can outlive the epoch guard.
+    pub fn get_owned(&self) -> T {
+        (**self).clone()
+    }
+}
+
+impl<T> Deref for EbrCellReadTxn<T>
+    where T: Send + Sync + 'static
+{
     type Target = T;
 
     /// Derference and access the value within the read transaction.
@@ -216,7 +299,8 @@ mod tests {
     use std::sync::atomic::{AtomicUsize, Ordering};
 
     use super::EbrCell;
-    use crossbeam_utils::scoped;
+    use crossbeam_epoch as epoch;
+    use crossbeam_utils::thread::scope;
 
     #[test]
     fn test_simple_create() {
@@ -251,6 +335,101 @@ mod tests {
         assert_eq!(*cc_rotxn_a, 0);
     }
 
+    #[test]
+    fn test_abort() {
+        let data: i64 = 0;
+        let cc = EbrCell::new(data);
+
+        let mut cc_wrtxn = cc.begin_write_txn();
+        *cc_wrtxn.get_mut() = 1;
+        cc_wrtxn.abort();
+
+        let cc_rotxn = cc.begin_read_txn();
+        assert_eq!(*cc_rotxn, 0);
+        // The write lock was released.
+        let mut cc_wrtxn = cc.begin_write_txn();
+        assert_eq!(*cc_wrtxn.get_mut(), 0);
+    }
+
+    #[test]
+    fn test_try_begin_write_txn() {
+        let data: i64 = 0;
+        let cc = EbrCell::new(data);
+
+        let mut cc_wrtxn = cc.try_begin_write_txn().unwrap();
+        *cc_wrtxn.get_mut() = 1;
+        // We are contended by our own write.
+        assert!(cc.try_begin_write_txn().is_none());
+        cc_wrtxn.commit();
+
+        let mut cc_wrtxn = cc.try_begin_write_txn().unwrap();
+        assert_eq!(*cc_wrtxn.get_mut(), 1);
+    }
+
+    #[test]
+    fn test_update() {
+        let data: i64 = 0;
+        let cc = EbrCell::new(data);
+        cc.update(|v| *v += 1);
+        assert_eq!(*cc.begin_read_txn(), 1);
+
+        scope(|scope| {
+            let cc_ref = &cc;
+            let _writers: Vec<_> = (0..4).map(|_| {
+                scope.spawn(move || {
+                    for _ in 0..250 {
+                        cc_ref.update(|v| *v += 1);
+                    }
+                })
+            }).collect();
+        });
+        assert_eq!(*cc.begin_read_txn(), 1001);
+    }
+
+    #[test]
+    fn test_get_owned() {
+        let cc = EbrCell::new(vec![1, 2, 3]);
+        let owned = {
+            let cc_rotxn = cc.begin_read_txn();
+            cc_rotxn.get_owned()
+        };
+
+        for i in 0..64 {
+            cc.update(|v| v.push(i));
+            // Encourage the old generations to actually be freed.
+            epoch::pin().flush();
+        }
+
+        assert_eq!(owned, vec![1, 2, 3]);
+        assert_eq!(cc.begin_read_txn().len(), 67);
+    }
+
+    #[test]
+    fn test_read_txn_clone() {
+        let cc = EbrCell::new(vec![1, 2, 3]);
+        let cc_rotxn_b = {
+            let cc_rotxn_a = cc.begin_read_txn();
+            cc_rotxn_a.clone()
+        };
+
+        for i in 0..64 {
+            cc.update(|v| v.push(i));
+            epoch::pin().flush();
+        }
+
+        let cc_rotxn_c = cc_rotxn_b.clone();
+        drop(cc_rotxn_b);
+        assert_eq!(*cc_rotxn_c, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_read_txn_debug() {
+        let cc = EbrCell::new(vec![1, 2, 3]);
+        let cc_rotxn = cc.begin_read_txn();
+        let s = format!("{:?}", cc_rotxn);
+        assert!(s.contains("[1, 2, 3]"));
+    }
+
     fn mt_writer(cc: &EbrCell<i64>) {
         let mut last_value: i64 = 0;
         while last_value < 500 {
@@ -284,7 +463,7 @@ mod tests {
         let data: i64 = 0;
         let cc = EbrCell::new(data);
 
-        scoped::scope(|scope| {
+        scope(|scope| {
             let cc_ref = &cc;
 
             let _readers: Vec<_> = (0..7).map(|_| {
@@ -330,6 +509,7 @@ mod tests {
                 }
                 cc_wrtxn.commit();
             }
+            cc.flush();
         }
     }
 
@@ -338,7 +518,7 @@ mod tests {
         let data = TestGcWrapper{data: 0};
         let cc = EbrCell::new(data);
 
-        scoped::scope(|scope| {
+        scope(|scope| {
             let cc_ref = &cc;
             let _writers: Vec<_> = (0..3).map(|_| {
                 scope.spawn(move || {
@@ -348,6 +528,21 @@ mod tests {
         });
 
         assert!(GC_COUNT.load(Ordering::Acquire) >= 50);
+
+        // With flushing, the old values of further commits are collected
+        // without needing more writes to trigger it.
+        let before = GC_COUNT.load(Ordering::Acquire);
+        for _ in 0..10 {
+            cc.update(|v| v.data += 1);
+            cc.flush();
+        }
+        for _ in 0..1000 {
+            if GC_COUNT.load(Ordering::Acquire) >= before + 10 {
+                break;
+            }
+            cc.flush();
+        }
+        assert!(GC_COUNT.load(Ordering::Acquire) >= before + 10);
     }
 
 }
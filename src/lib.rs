@@ -1,28 +1,102 @@
 extern crate crossbeam_epoch;
 extern crate crossbeam_utils;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(feature = "rayon")]
+extern crate rayon;
 
 pub mod cowcell;
 // Linearised cowcell with arc for Bptree
 pub mod lincowcell;
-// pub mod ebrcell;
+pub mod ebrcell;
 pub mod bst;
 
-use std::ops::{BitAnd, BitOr};
+use std::ops::{BitAnd, BitOr, BitXor, BitAndAssign, BitOrAssign};
 use std::fmt;
+use std::error;
 use std::iter::FromIterator;
-use std::cmp::Ordering;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::iter;
+use std::slice;
+use std::vec;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+#[cfg(feature = "serde")]
+use serde::ser::SerializeSeq;
+#[cfg(feature = "serde")]
+use serde::de::Error as DeError;
 
 pub trait AndNot<RHS = Self> {
     type Output;
     fn andnot(self, rhs: RHS) -> Self::Output;
 }
 
+pub trait AndNotAssign<RHS = Self> {
+    fn andnot_assign(&mut self, rhs: RHS);
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IDLError {
+    Unsorted,
+    EmptyMask,
+    Truncated,
+    DuplicateId,
+    Unaligned,
+    OutOfBounds,
+}
+
+impl fmt::Display for IDLError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IDLError::Unsorted => write!(f, "ids or ranges are not in ascending order"),
+            IDLError::EmptyMask => write!(f, "range has an empty mask"),
+            IDLError::Truncated => write!(f, "input was truncated"),
+            IDLError::DuplicateId => write!(f, "id was present more than once"),
+            IDLError::Unaligned => write!(f, "range base is not a multiple of 64"),
+            IDLError::OutOfBounds => write!(f, "id is above the allowed maximum"),
+        }
+    }
+}
+
+impl error::Error for IDLError {}
+
 pub trait IDL {
     fn push_id(&mut self, value: u64);
+    fn remove_id(&mut self, value: u64);
     fn len(&self) -> usize;
+    fn contains(&self, id: u64) -> bool;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Insert every id in `ids`. Any order is accepted, but implementations
+    /// may take a faster path when the slice is strictly ascending.
+    fn push_ids(&mut self, ids: &[u64]) {
+        for id in ids {
+            self.push_id(*id);
+        }
+    }
+
+    /// As `push_id`, but refuse ids above `max` with `IDLError::OutOfBounds`
+    /// rather than growing the set out to them.
+    fn push_id_bounded(&mut self, value: u64, max: u64) -> Result<(), IDLError> {
+        if value > max {
+            return Err(IDLError::OutOfBounds);
+        }
+        self.push_id(value);
+        Ok(())
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct IDLSimple(Vec<u64>);
 
 impl IDLSimple {
@@ -30,91 +104,85 @@ impl IDLSimple {
         IDLSimple(Vec::with_capacity(128))
     }
 
-    pub fn from_u64(id: u64) -> Self {
-        let mut new = IDLSimple::new();
-        new.push_id(id);
-        new
+    /// Approximate bytes consumed, including unused capacity.
+    pub fn mem_size(&self) -> usize {
+        self.0.capacity() * mem::size_of::<u64>() + mem::size_of::<Self>()
     }
 
-    fn bstbitand(&self, candidate: &u64) -> Self {
-        let mut result = IDLSimple::new();
-        if let Ok(_idx) = self.0.binary_search(candidate) {
-            result.0.push(*candidate);
-        };
-        result
+    /// The first id in the set, or `None` if it is empty.
+    pub fn first(&self) -> Option<u64> {
+        self.0.first().cloned()
     }
-}
 
-impl IDL for IDLSimple {
-    fn push_id(&mut self, value: u64) {
-        let &mut IDLSimple(ref mut list) = self;
-        list.push(value)
+    /// The last id in the set, or `None` if it is empty.
+    pub fn last(&self) -> Option<u64> {
+        self.0.last().cloned()
     }
 
-    fn len(&self) -> usize {
-        let &IDLSimple(ref list) = self;
-        list.len()
+    /// Sort the ids into ascending order. `push_id` already keeps the set
+    /// ordered, so this is only needed to normalise a list that was built
+    /// some other way; follow it with `dedup`.
+    pub fn sort(&mut self) {
+        self.0.sort_unstable();
     }
 
-}
-
-impl FromIterator<u64> for IDLSimple {
-    fn from_iter<I: IntoIterator<Item=u64>>(iter: I) -> Self {
-        let mut list = Vec::with_capacity(8);
-        for i in iter {
-            list.push(i);
-        }
-        IDLSimple(list)
+    /// Remove consecutive duplicate ids. Call after `sort`.
+    pub fn dedup(&mut self) {
+        self.0.dedup();
     }
-}
-
-#[derive(Debug)]
-pub struct IDLSimpleIter<'b> {
-    simpleiter: std::slice::Iter<'b, u64>,
-}
 
-impl<'b> Iterator for IDLSimpleIter<'b> {
-    type Item = u64;
+    pub fn from_u64(id: u64) -> Self {
+        let mut new = IDLSimple::new();
+        new.push_id(id);
+        new
+    }
 
-    fn next(&mut self) -> Option<u64> {
-        if let Some(id) = self.simpleiter.next() {
-            Some(id.clone())
+    pub fn intersection(&self, other: &Self) -> Self {
+        let (small, large) = if self.0.len() <= other.0.len() {
+            (self, other)
         } else {
-            None
+            (other, self)
+        };
+        if small.0.is_empty() {
+            return IDLSimple::new();
         }
-    }
-}
-
-impl<'b> IntoIterator for &'b IDLSimple {
-    type Item = u64;
-    type IntoIter = IDLSimpleIter<'b>;
-
-    fn into_iter(self) -> Self::IntoIter {
-        IDLSimpleIter {
-            simpleiter: (&self.0).into_iter(),
+        // Galloping costs about 2 * log2(gap) per element of small, where
+        // the gap is how far apart the elements of small land in large. The
+        // merge touches every element of both.
+        let gap = large.0.len() / small.0.len();
+        let search_cost = small.0.len() * 2 * (gap.ilog2() as usize + 1);
+        if search_cost < small.0.len() + large.0.len() {
+            small.small_intersect(large)
+        } else {
+            self.merge_intersection(other)
         }
     }
-}
-
-impl BitAnd for IDLSimple
-{
-    type Output = Self;
 
-    fn bitand(self, other: Self) -> Self {
-
-        if self.0.len() == 1 {
-            return other.bstbitand(self.0.first().unwrap());
-        } else if other.0.len() == 1 {
-            return self.bstbitand(other.0.first().unwrap());
+    // Gallop through other for each of our ids. Our ids are sorted, so each
+    // search starts where the last one landed, and stays cheap when the
+    // next match is close by.
+    fn small_intersect(&self, other: &Self) -> Self {
+        let mut result = IDLSimple::new();
+        let mut rest = &other.0[..];
+        for id in &self.0 {
+            rest = &rest[gallop(rest, |x| x < id)..];
+            match rest.first() {
+                Some(x) if x == id => {
+                    result.0.push(*id);
+                    rest = &rest[1..];
+                }
+                Some(_) => {}
+                None => break,
+            }
         }
+        result
+    }
 
-        let IDLSimple(rhs) = other;
-        let IDLSimple(lhs) = self;
-
+    fn merge_intersection(&self, other: &Self) -> Self {
         let mut result = IDLSimple::new();
 
-        let mut liter = lhs.iter();
-        let mut riter = rhs.iter();
+        let mut liter = self.0.iter();
+        let mut riter = other.0.iter();
 
         let mut lnext = liter.next();
         let mut rnext = riter.next();
@@ -138,18 +206,12 @@ impl BitAnd for IDLSimple
         result
 
     }
-}
-
-impl BitOr for IDLSimple
-{
-    type Output = Self;
 
-    fn bitor(self, IDLSimple(rhs): Self) -> Self {
-        let IDLSimple(lhs) = self;
+    pub fn union(&self, other: &Self) -> Self {
         let mut result = IDLSimple::new();
 
-        let mut liter = lhs.iter();
-        let mut riter = rhs.iter();
+        let mut liter = self.0.iter();
+        let mut riter = other.0.iter();
 
         let mut lnext = liter.next();
         let mut rnext = riter.next();
@@ -186,18 +248,13 @@ impl BitOr for IDLSimple
         }
         result
     }
-}
-
-impl AndNot for IDLSimple {
-    type Output = Self;
 
-    fn andnot(self, IDLSimple(rhs): Self) -> Self {
-        let IDLSimple(lhs) = self;
+    pub fn difference(&self, other: &Self) -> Self {
         let mut result = IDLSimple::new();
 
         /*  LEFT is the a not b, IE a - b set wise. */
-        let mut liter = lhs.iter();
-        let mut riter = rhs.iter();
+        let mut liter = self.0.iter();
+        let mut riter = other.0.iter();
 
         let mut lnext = liter.next();
         let mut rnext = riter.next();
@@ -227,183 +284,483 @@ impl AndNot for IDLSimple {
 
         result
     }
-}
 
-#[derive(Debug)]
-struct IDLRange {
-    range: u64,
-    mask: u64,
-}
+    /// The number of ids in `self & other`, without building the result.
+    pub fn intersection_count(&self, other: &Self) -> usize {
+        let mut count = 0;
 
-// To make binary search, Ord only applies to range.
+        let mut liter = self.0.iter();
+        let mut riter = other.0.iter();
 
-impl Ord for IDLRange {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.range.cmp(&other.range)
-    }
-}
+        let mut lnext = liter.next();
+        let mut rnext = riter.next();
 
-impl PartialOrd for IDLRange {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+        while let (Some(l), Some(r)) = (lnext, rnext) {
+            if l == r {
+                count += 1;
+                lnext = liter.next();
+                rnext = riter.next();
+            } else if l < r {
+                lnext = liter.next();
+            } else {
+                rnext = riter.next();
+            }
+        }
+        count
     }
 }
 
-impl PartialEq for IDLRange {
-    fn eq(&self, other: &Self) -> bool {
-        self.range == other.range && self.mask == other.mask
+impl Default for IDLSimple {
+    fn default() -> Self {
+        IDLSimple::new()
     }
 }
 
-impl Eq for IDLRange {}
+impl IDL for IDLSimple {
+    fn push_id(&mut self, value: u64) {
+        let &mut IDLSimple(ref mut list) = self;
+        // Keep the list sorted and unique, but don't search for the
+        // common ascending case.
+        match list.last() {
+            Some(last) if *last >= value => {
+                if let Err(idx) = list.binary_search(&value) {
+                    list.insert(idx, value);
+                }
+            }
+            _ => list.push(value),
+        }
+    }
 
-impl IDLRange {
-    fn new(range: u64, mask: u64) -> Self {
-        IDLRange {
-            range: range,
-            mask: mask,
+    fn remove_id(&mut self, value: u64) {
+        if let Ok(idx) = self.0.binary_search(&value) {
+            self.0.remove(idx);
         }
     }
 
-    fn push_id(&mut self, value: u64) {
-        let nmask = 1 << value;
-        self.mask ^= nmask;
+    fn len(&self) -> usize {
+        let &IDLSimple(ref list) = self;
+        list.len()
+    }
+
+    fn contains(&self, id: u64) -> bool {
+        self.0.binary_search(&id).is_ok()
     }
-}
 
-#[derive(PartialEq)]
-pub struct IDLBitRange {
-    list: Vec<IDLRange>,
 }
 
-impl IDLBitRange {
-    fn new() -> Self {
-        IDLBitRange {
-            list: Vec::new(),
+impl FromIterator<u64> for IDLSimple {
+    fn from_iter<I: IntoIterator<Item=u64>>(iter: I) -> Self {
+        let mut new = IDLSimple(Vec::with_capacity(8));
+        for i in iter {
+            new.push_id(i);
         }
-    }
-
-    pub fn from_u64(id: u64) -> Self {
-        let mut new = IDLBitRange::new();
-        new.push_id(id);
         new
     }
+}
 
-    fn bstbitand(&self, candidate: &IDLRange) -> Self {
-        let mut result = IDLBitRange::new();
-        if let Ok(idx) = self.list.binary_search(candidate) {
-            let existing = self.list.get(idx).unwrap();
-            let mask = existing.mask & candidate.mask;
-            if mask > 0 {
-                let newrange = IDLRange::new(candidate.range, mask);
-                result.list.push(newrange);
-            };
-        };
-        result
+impl Extend<u64> for IDLSimple {
+    fn extend<I: IntoIterator<Item=u64>>(&mut self, iter: I) {
+        for i in iter {
+            self.push_id(i);
+        }
     }
 }
 
-impl IDL for IDLBitRange {
-    fn push_id(&mut self, value: u64) {
-        // Get what range this should be
-        let bvalue: u64 = value % 64;
-        let range: u64 = value - bvalue;
+#[derive(Debug)]
+pub struct IDLSimpleIter<'b> {
+    simpleiter: std::slice::Iter<'b, u64>,
+}
 
-        // Get the highest IDLRange out:
-        if let Some(last) = self.list.last_mut() {
-            if (*last).range == range {
-                // Insert the bit.
-                (*last).push_id(bvalue);
-                return;
-            }
-        }
+impl<'b> Iterator for IDLSimpleIter<'b> {
+    type Item = u64;
 
-        // New takes a starting mask, not a raw bval, so shift it!
-        let newrange = IDLRange::new(range, 1 << bvalue);
-        self.list.push(newrange);
+    fn next(&mut self) -> Option<u64> {
+        if let Some(id) = self.simpleiter.next() {
+            Some(id.clone())
+        } else {
+            None
+        }
     }
 
-    fn len(&self) -> usize {
-        0
-        // Right now, this would require a complete walk of the bitmask.
-        // self.count
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.simpleiter.size_hint()
     }
 }
 
-impl FromIterator<u64> for IDLBitRange {
-    fn from_iter<I: IntoIterator<Item=u64>>(iter: I) -> Self {
-        let mut new = IDLBitRange {
-            list: Vec::new(),
-            // count: 0,
-        };
-        for i in iter {
-            new.push_id(i);
+impl<'b> ExactSizeIterator for IDLSimpleIter<'b> {}
+
+impl<'b> IntoIterator for &'b IDLSimple {
+    type Item = u64;
+    type IntoIter = IDLSimpleIter<'b>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IDLSimpleIter {
+            simpleiter: (&self.0).into_iter(),
         }
-        new
     }
 }
 
-impl BitAnd for IDLBitRange
+impl IntoIterator for IDLSimple {
+    type Item = u64;
+    type IntoIter = vec::IntoIter<u64>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl BitAnd for IDLSimple
 {
     type Output = Self;
 
     fn bitand(self, rhs: Self) -> Self {
-        /*
-         * If one candidate range has only a single range,
-         * we can do a much faster search / return.
-         */
-        /*
-         * lkrispen: comment out unless implemented for IDLsimple
-         * wibrown: Well, this doesn't exist today on IDL, so it's
-         * fair to take any improvement we can :) But I'll add it
-         * to IDL simple anyway.
-         */
-        if self.list.len() == 1 {
-            return rhs.bstbitand(self.list.first().unwrap());
-        } else if rhs.list.len() == 1 {
-            return self.bstbitand(rhs.list.first().unwrap());
-        }
+        self.intersection(&rhs)
+    }
+}
 
-        let mut result = IDLBitRange::new();
+impl BitOr for IDLSimple
+{
+    type Output = Self;
 
-        let mut liter = self.list.iter();
-        let mut riter = rhs.list.iter();
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(&rhs)
+    }
+}
 
-        let mut lnextrange = liter.next();
-        let mut rnextrange = riter.next();
+impl BitXor for IDLSimple
+{
+    type Output = Self;
 
-        while lnextrange.is_some() && rnextrange.is_some() {
-            let l = lnextrange.unwrap();
-            let r = rnextrange.unwrap();
+    fn bitxor(self, IDLSimple(rhs): Self) -> Self {
+        let IDLSimple(lhs) = self;
+        let mut result = IDLSimple::new();
+
+        let mut liter = lhs.iter();
+        let mut riter = rhs.iter();
+
+        let mut lnext = liter.next();
+        let mut rnext = riter.next();
+
+        while lnext.is_some() && rnext.is_some() {
+            let l = lnext.unwrap();
+            let r = rnext.unwrap();
+
+            if l == r {
+                lnext = liter.next();
+                rnext = riter.next();
+            } else if l < r {
+                result.push_id(*l);
+                lnext = liter.next();
+            } else {
+                result.push_id(*r);
+                rnext = riter.next();
+            }
+        };
+
+        while lnext.is_some() {
+            let l = lnext.unwrap();
+            result.push_id(*l);
+            lnext = liter.next();
+        }
+
+        while rnext.is_some() {
+            let r = rnext.unwrap();
+            result.push_id(*r);
+            rnext = riter.next();
+        }
+        result
+    }
+}
+
+impl AndNot for IDLSimple {
+    type Output = Self;
+
+    fn andnot(self, rhs: Self) -> Self {
+        self.difference(&rhs)
+    }
+}
+
+impl<'a> BitAnd<&'a IDLSimple> for &'a IDLSimple {
+    type Output = IDLSimple;
+
+    fn bitand(self, rhs: &'a IDLSimple) -> IDLSimple {
+        self.intersection(rhs)
+    }
+}
+
+impl<'a> BitOr<&'a IDLSimple> for &'a IDLSimple {
+    type Output = IDLSimple;
+
+    fn bitor(self, rhs: &'a IDLSimple) -> IDLSimple {
+        self.union(rhs)
+    }
+}
+
+impl<'a> AndNot<&'a IDLSimple> for &'a IDLSimple {
+    type Output = IDLSimple;
+
+    fn andnot(self, rhs: &'a IDLSimple) -> IDLSimple {
+        self.difference(rhs)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[repr(C)]
+struct IDLRange {
+    range: u64,
+    mask: u64,
+}
+
+// To make binary search, Ord only applies to range.
+
+impl Ord for IDLRange {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.range.cmp(&other.range)
+    }
+}
+
+impl PartialOrd for IDLRange {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for IDLRange {
+    fn eq(&self, other: &Self) -> bool {
+        self.range == other.range && self.mask == other.mask
+    }
+}
+
+impl Eq for IDLRange {}
+
+impl IDLRange {
+    fn new(range: u64, mask: u64) -> Self {
+        IDLRange {
+            range: range,
+            mask: mask,
+        }
+    }
+
+    // Returns true if the bit was not already set.
+    fn push_id(&mut self, value: u64) -> bool {
+        let nmask = 1 << value;
+        let inserted = self.mask & nmask == 0;
+        self.mask |= nmask;
+        inserted
+    }
+
+    // Returns true if the bit was set.
+    fn remove_id(&mut self, value: u64) -> bool {
+        let nmask = 1 << value;
+        let removed = self.mask & nmask > 0;
+        self.mask &= !nmask;
+        removed
+    }
+
+    // Remove and return the lowest id in this range.
+    fn pop_first(&mut self) -> Option<u64> {
+        if self.mask == 0 {
+            return None;
+        }
+        let bvalue = self.mask.trailing_zeros() as u64;
+        self.mask &= self.mask - 1;
+        Some(self.range + bvalue)
+    }
+
+    // Remove and return the highest id in this range.
+    fn pop_last(&mut self) -> Option<u64> {
+        if self.mask == 0 {
+            return None;
+        }
+        let bvalue = 63 - self.mask.leading_zeros() as u64;
+        self.mask &= !(1 << bvalue);
+        Some(self.range + bvalue)
+    }
+}
+
+#[derive(PartialEq, Eq, Clone)]
+pub struct IDLBitRange {
+    list: Vec<IDLRange>,
+    // Cached cardinality, so that len() doesn't need to walk the masks.
+    count: usize,
+}
+
+impl IDLBitRange {
+    fn new() -> Self {
+        IDLBitRange {
+            list: Vec::new(),
+            count: 0,
+        }
+    }
+
+    pub fn from_u64(id: u64) -> Self {
+        let mut new = IDLBitRange::new();
+        new.push_id(id);
+        new
+    }
+
+    /// Build from ids that are already ascending and unique. This skips all
+    /// ordering checks, so unsorted input produces an invalid set. Use
+    /// `try_from_iter` if the input is not trusted.
+    pub fn from_sorted_unchecked<I: IntoIterator<Item=u64>>(iter: I) -> Self {
+        let mut new = IDLBitRange::new();
+        for id in iter {
+            let bvalue: u64 = id % 64;
+            let range: u64 = id - bvalue;
+            match new.list.last_mut() {
+                Some(last) if last.range == range => last.mask |= 1 << bvalue,
+                _ => new.list.push(IDLRange::new(range, 1 << bvalue)),
+            }
+            new.count += 1;
+        }
+        new
+    }
+
+    /// Build from a slice of strictly ascending ids, setting the bits of
+    /// each range in a single pass. Ascending order is only checked in
+    /// debug builds, so as with `from_sorted_unchecked`, unsorted input
+    /// produces an invalid set.
+    pub fn from_sorted_slice(ids: &[u64]) -> Self {
+        debug_assert!(ids.windows(2).all(|w| w[0] < w[1]), "ids are not strictly ascending");
+        let mut new = IDLBitRange::new();
+        if let (Some(first), Some(last)) = (ids.first(), ids.last()) {
+            // No more ranges than ids, or than the span can hold.
+            new.reserve(ids.len().min(((last - first) / 64 + 1) as usize));
+        }
+        let mut rest = ids;
+        while let Some(first) = rest.first() {
+            let range = first - first % 64;
+            // The ids that fall in this range are all at the front.
+            let end = rest.partition_point(|id| id - range < 64);
+            let mask = rest[..end].iter().fold(0, |mask, id| mask | 1 << (id - range));
+            new.list.push(IDLRange::new(range, mask));
+            new.count += end;
+            rest = &rest[end..];
+        }
+        new
+    }
+
+    /// Build from ids that must be strictly ascending, returning an error
+    /// if an id is out of order or repeated.
+    pub fn try_from_iter<I: IntoIterator<Item=u64>>(iter: I) -> Result<Self, IDLError> {
+        let mut new = IDLBitRange::new();
+        let mut prev: Option<u64> = None;
+        for id in iter {
+            match prev {
+                Some(p) if p == id => return Err(IDLError::DuplicateId),
+                Some(p) if p > id => return Err(IDLError::Unsorted),
+                _ => {}
+            }
+            prev = Some(id);
+            new.push_id(id);
+        }
+        Ok(new)
+    }
+
+    fn bstbitand(&self, candidate: &IDLRange) -> Self {
+        let mut result = IDLBitRange::new();
+        if let Ok(idx) = self.list.binary_search(candidate) {
+            let existing = self.list.get(idx).unwrap();
+            let mask = existing.mask & candidate.mask;
+            if mask > 0 {
+                let newrange = IDLRange::new(candidate.range, mask);
+                result.list.push(newrange);
+                result.count += mask.count_ones() as usize;
+            };
+        };
+        result
+    }
+
+    pub fn intersection(&self, other: &Self) -> Self {
+        /*
+         * If one candidate range has only a single range,
+         * we can do a much faster search / return.
+         */
+        /*
+         * lkrispen: comment out unless implemented for IDLsimple
+         * wibrown: Well, this doesn't exist today on IDL, so it's
+         * fair to take any improvement we can :) But I'll add it
+         * to IDL simple anyway.
+         */
+        if self.list.len() == 1 {
+            return other.bstbitand(self.list.first().unwrap());
+        } else if other.list.len() == 1 {
+            return self.bstbitand(other.list.first().unwrap());
+        }
+
+        // If one side ends before the other starts there is nothing to do.
+        // Smaller gaps within the lists are galloped over in the loop.
+        if !self.ranges_overlap(other) {
+            return IDLBitRange::new();
+        }
+
+        let mut result = IDLBitRange::new();
+        // The result can't have more ranges than the smaller input.
+        result.reserve(self.list.len().min(other.list.len()));
+
+        let mut liter = self.list.iter();
+        let mut riter = other.list.iter();
+
+        let mut lnextrange = liter.next();
+        let mut rnextrange = riter.next();
+
+        while lnextrange.is_some() && rnextrange.is_some() {
+            let l = lnextrange.unwrap();
+            let r = rnextrange.unwrap();
 
             if l.range == r.range {
                 let mask = l.mask & r.mask;
                 if mask > 0 {
                     let newrange = IDLRange::new(l.range, mask);
                     result.list.push(newrange);
+                    result.count += mask.count_ones() as usize;
+                }
+                // Dense inputs tend to have long runs of matching ranges,
+                // so AND those together in one wide pass.
+                #[cfg(feature = "simd")]
+                {
+                    let lrest = liter.as_slice();
+                    let rrest = riter.as_slice();
+                    let run = lrest.iter()
+                        .zip(rrest.iter())
+                        .take_while(|&(a, b)| a.range == b.range)
+                        .count();
+                    simd::and_ranges(&lrest[..run], &rrest[..run], &mut result);
+                    liter = lrest[run..].iter();
+                    riter = rrest[run..].iter();
                 }
                 lnextrange = liter.next();
                 rnextrange = riter.next();
             } else if l.range < r.range {
-                lnextrange = liter.next();
+                // When one side is much larger, it may have long stretches
+                // with nothing in the other, so gallop over them.
+                lnextrange = gallop_next(&mut liter, r.range);
             } else {
-                rnextrange = riter.next();
+                rnextrange = gallop_next(&mut riter, l.range);
             }
 
         }
         result
     }
-}
 
-impl BitOr for IDLBitRange
-{
-    type Output = Self;
+    // False if one side ends before the other starts, or either is empty,
+    // so that no range can be shared.
+    fn ranges_overlap(&self, other: &Self) -> bool {
+        match (self.list.first(), self.list.last(), other.list.first(), other.list.last()) {
+            (Some(lfirst), Some(llast), Some(rfirst), Some(rlast)) => {
+                llast.range >= rfirst.range && rlast.range >= lfirst.range
+            }
+            _ => false,
+        }
+    }
 
-    fn bitor(self, rhs: Self) -> Self {
+    pub fn union(&self, other: &Self) -> Self {
         let mut result = IDLBitRange::new();
+        // The result has at least as many ranges as the larger input.
+        result.reserve(self.list.len().max(other.list.len()));
 
         let mut liter = self.list.iter();
-        let mut riter = rhs.list.iter();
+        let mut riter = other.list.iter();
 
         let mut lnextrange = liter.next();
         let mut rnextrange = riter.next();
@@ -425,6 +782,7 @@ impl BitOr for IDLBitRange
             };
             let newrange = IDLRange::new(range, mask);
             result.list.push(newrange);
+            result.count += mask.count_ones() as usize;
         }
 
         while lnextrange.is_some() {
@@ -432,6 +790,7 @@ impl BitOr for IDLBitRange
 
             let newrange = IDLRange::new(l.range, l.mask);
             result.list.push(newrange);
+            result.count += l.mask.count_ones() as usize;
             lnextrange = liter.next();
         }
 
@@ -440,20 +799,19 @@ impl BitOr for IDLBitRange
 
             let newrange = IDLRange::new(r.range, r.mask);
             result.list.push(newrange);
+            result.count += r.mask.count_ones() as usize;
             rnextrange = riter.next();
         }
         result
     }
-}
-
-impl AndNot for IDLBitRange {
-    type Output = Self;
 
-    fn andnot(self, rhs: Self) -> Self {
+    pub fn difference(&self, other: &Self) -> Self {
         let mut result = IDLBitRange::new();
+        // The result can't have more ranges than self.
+        result.reserve(self.list.len());
 
         let mut liter = self.list.iter();
-        let mut riter = rhs.list.iter();
+        let mut riter = other.list.iter();
 
         let mut lnextrange = liter.next();
         let mut rnextrange = riter.next();
@@ -467,10 +825,15 @@ impl AndNot for IDLBitRange {
                 if mask > 0 {
                     let newrange = IDLRange::new(l.range, mask);
                     result.list.push(newrange);
+                    result.count += mask.count_ones() as usize;
                 }
                 lnextrange = liter.next();
                 rnextrange = riter.next();
             } else if l.range < r.range {
+                // Nothing in rhs can remove from this range, keep it.
+                let newrange = IDLRange::new(l.range, l.mask);
+                result.list.push(newrange);
+                result.count += l.mask.count_ones() as usize;
                 lnextrange = liter.next();
             } else {
                 rnextrange = riter.next();
@@ -482,90 +845,1680 @@ impl AndNot for IDLBitRange {
 
             let newrange = IDLRange::new(l.range, l.mask);
             result.list.push(newrange);
+            result.count += l.mask.count_ones() as usize;
             lnextrange = liter.next();
         }
         result
     }
-}
 
-#[derive(Debug)]
-pub struct IDLBitRangeIter<'a> {
-    // rangeiter: std::vec::IntoIter<IDLRange>,
-    rangeiter: std::slice::Iter<'a, IDLRange>,
-    currange: Option<&'a IDLRange>,
-    curbit: u64,
-}
+    /// Add the ids of `other` to self in place, reusing our range list's
+    /// allocation. This is cheapest when self is the larger set.
+    pub fn union_into(&mut self, other: &IDLBitRange) {
+        // Count the ranges we lack, so the list only has to grow once.
+        let mut missing = 0;
+        let mut liter = self.list.iter().peekable();
+        for r in &other.list {
+            while liter.peek().map(|l| l.range < r.range).unwrap_or(false) {
+                liter.next();
+            }
+            match liter.peek() {
+                Some(l) if l.range == r.range => {}
+                _ => missing += 1,
+            }
+        }
 
-impl<'a>Iterator for IDLBitRangeIter<'a> {
-    type Item = u64;
+        // Merge from the back, so each range is moved at most once and
+        // never over one that has yet to be moved.
+        let mut i = self.list.len();
+        let mut j = other.list.len();
+        self.list.resize(i + missing, IDLRange::new(0, 0));
+        let mut w = self.list.len();
+        while j > 0 {
+            let r = &other.list[j - 1];
+            w -= 1;
+            if i > 0 && self.list[i - 1].range > r.range {
+                self.list[w] = self.list[i - 1].clone();
+                i -= 1;
+            } else if i > 0 && self.list[i - 1].range == r.range {
+                let mut l = self.list[i - 1].clone();
+                self.count += (r.mask & !l.mask).count_ones() as usize;
+                l.mask |= r.mask;
+                self.list[w] = l;
+                i -= 1;
+                j -= 1;
+            } else {
+                self.list[w] = r.clone();
+                self.count += r.mask.count_ones() as usize;
+                j -= 1;
+            }
+        }
+    }
 
-    fn next(&mut self) -> Option<u64> {
-        while self.currange.is_some() {
-            let range = self.currange.unwrap();
-            while self.curbit < 64 {
-                let m: u64 = 1 << self.curbit;
-                let candidate: u64 = range.mask & m;
-                if candidate > 0 {
-                    let result = Some(self.curbit + range.range);
-                    self.curbit += 1;
-                    return result;
-                }
-                self.curbit += 1;
+    /// Append the ids of `other` when they are all above ours, as with
+    /// shards partitioned by id, without merging. The two sets may share a
+    /// range at the seam. If any id of `other` is not above our last id,
+    /// `IDLError::Unsorted` is returned and self is unchanged.
+    pub fn concat_disjoint(&mut self, other: IDLBitRange) -> Result<(), IDLError> {
+        if self.list.is_empty() {
+            // Nothing to keep, so take over other's list as is.
+            *self = other;
+            return Ok(());
+        }
+        let mut seam = 0;
+        if let (Some(last), Some(first)) = (self.last(), other.first()) {
+            if last >= first {
+                return Err(IDLError::Unsorted);
+            }
+            let l = self.list.last_mut().unwrap();
+            if l.range == other.list[0].range {
+                l.mask |= other.list[0].mask;
+                seam = 1;
             }
-            self.currange = self.rangeiter.next();
-            self.curbit = 0;
         }
-        None
+        self.count += other.count;
+        self.list.extend(other.list.into_iter().skip(seam));
+        Ok(())
     }
-}
 
-impl<'a> IntoIterator for &'a IDLBitRange {
-    type Item = u64;
-    type IntoIter = IDLBitRangeIter<'a>;
+    /// Iterate the compressed representation as `(base, mask)` pairs. Bases
+    /// are ascending multiples of 64 and masks are never zero.
+    pub fn ranges<'a>(&'a self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        self.list.iter().map(|r| (r.range, r.mask))
+    }
 
-    fn into_iter(self) -> IDLBitRangeIter<'a> {
-        let mut liter = (&self.list).into_iter();
-        let nrange = liter.next();
-        IDLBitRangeIter {
-            rangeiter: liter,
-            currange: nrange,
-            curbit: 0,
+    /// Build from `(base, mask)` pairs as produced by `ranges`. Each base
+    /// must be a multiple of 64 and greater than the last, and each mask
+    /// must be non-zero, else an error is returned.
+    pub fn from_ranges<I: IntoIterator<Item = (u64, u64)>>(iter: I) -> Result<Self, IDLError> {
+        let iter = iter.into_iter();
+        let mut new = IDLBitRange::new();
+        new.reserve(iter.size_hint().0);
+        for (range, mask) in iter {
+            if range % 64 != 0 {
+                return Err(IDLError::Unaligned);
+            }
+            if mask == 0 {
+                return Err(IDLError::EmptyMask);
+            }
+            if let Some(last) = new.list.last() {
+                if last.range >= range {
+                    return Err(IDLError::Unsorted);
+                }
+            }
+            new.list.push(IDLRange::new(range, mask));
+            new.count += mask.count_ones() as usize;
         }
+        Ok(new)
     }
-}
 
-impl fmt::Debug for IDLBitRange {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "IDLBitRange (compressed) {:?} (decompressed) [ ", self.list).unwrap();
-        for id in self {
-            write!(f, "{}, ", id).unwrap();
+    /// Add every id set in `mask` to the range starting at `base`, in one
+    /// step. `base` must be a multiple of 64, else `IDLError::Unaligned` is
+    /// returned and the set is unchanged.
+    pub fn push_range(&mut self, base: u64, mask: u64) -> Result<(), IDLError> {
+        if base % 64 != 0 {
+            return Err(IDLError::Unaligned);
         }
-        write!(f, "]")
+        if mask == 0 {
+            return Ok(());
+        }
+        match self.list.binary_search_by(|r| r.range.cmp(&base)) {
+            Ok(idx) => {
+                let existing = &mut self.list[idx];
+                self.count += (mask & !existing.mask).count_ones() as usize;
+                existing.mask |= mask;
+            }
+            Err(idx) => {
+                self.list.insert(idx, IDLRange::new(base, mask));
+                self.count += mask.count_ones() as usize;
+            }
+        }
+        Ok(())
     }
-}
 
+    /// Reserve capacity for at least `additional` more ranges.
+    pub fn reserve(&mut self, additional: usize) {
+        self.list.reserve(additional);
+    }
 
+    /// Release any unused capacity in the range list.
+    pub fn shrink_to_fit(&mut self) {
+        self.list.shrink_to_fit();
+    }
 
-#[cfg(test)]
-mod tests {
-    // use test::Bencher;
-    use super::{IDLSimple, IDLBitRange, AndNot};
-    use std::iter::FromIterator;
+    /// Drop any ranges with an empty mask and recount the ids. Nothing in
+    /// this crate leaves such ranges behind, but a list put together by
+    /// other means can be tidied with this before use. The ranges must
+    /// still be ascending, which is checked in debug builds.
+    pub fn optimize(&mut self) {
+        self.list.retain(|r| r.mask != 0);
+        debug_assert!(
+            self.list.windows(2).all(|w| w[0].range < w[1].range),
+            "ranges are not strictly ascending"
+        );
+        self.count = self.list.iter().map(|r| r.mask.count_ones() as usize).sum();
+    }
 
-    #[test]
-    fn test_simple_intersection_1() {
-        let idl_a = IDLSimple::from_iter(vec![1, 2, 3]);
-        let idl_b = IDLSimple::from_iter(vec![2]);
-        let idl_expect = IDLSimple::from_iter(vec![2]);
+    /// Remove all ids, keeping the allocated capacity for reuse.
+    pub fn clear(&mut self) {
+        self.list.clear();
+        self.count = 0;
+    }
 
-        let idl_result = idl_a & idl_b;
-        assert_eq!(idl_result, idl_expect);
+    /// Approximate bytes consumed, including unused capacity.
+    ///
+    /// Each range costs 16 bytes against 8 per id in `IDLSimple`, so when
+    /// ids are scattered (fewer than two per range) `IDLSimple::mem_size`
+    /// will be the smaller of the two. Dense ids pack up to 64 per range.
+    pub fn mem_size(&self) -> usize {
+        self.list.capacity() * mem::size_of::<IDLRange>() + mem::size_of::<Self>()
     }
 
-    #[test]
-    fn test_range_intersection_1() {
-        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3]);
-        let idl_b = IDLBitRange::from_iter(vec![2]);
+    /// The number of ranges stored.
+    pub fn count_ranges(&self) -> usize {
+        self.list.len()
+    }
+
+    /// The fraction of bits set across the stored ranges, from 1/64 when
+    /// each range holds a single id to 1.0 when every range is full.
+    /// An empty set has a density of 0.0.
+    pub fn density(&self) -> f64 {
+        if self.list.is_empty() {
+            return 0.0;
+        }
+        self.count as f64 / (self.count_ranges() * 64) as f64
+    }
+
+    /// Intersect in parallel. Our ranges are split into chunks, each
+    /// chunk is intersected with the slice of `other` covering the same
+    /// keys, and the ordered results are joined. Only worth it for very
+    /// large inputs.
+    #[cfg(feature = "rayon")]
+    pub fn par_intersection(&self, other: &Self) -> Self {
+        const CHUNK: usize = 4096;
+
+        let parts: Vec<Vec<IDLRange>> = self.list
+            .par_chunks(CHUNK)
+            .map(|chunk| {
+                let lo = chunk[0].range;
+                let hi = chunk[chunk.len() - 1].range;
+                let start = other.list.partition_point(|r| r.range < lo);
+                let end = other.list.partition_point(|r| r.range <= hi);
+
+                let mut part = Vec::new();
+                let mut liter = chunk.iter().peekable();
+                let mut riter = other.list[start..end].iter().peekable();
+                while let (Some(l), Some(r)) = (liter.peek(), riter.peek()) {
+                    match l.range.cmp(&r.range) {
+                        Ordering::Equal => {
+                            let mask = l.mask & r.mask;
+                            if mask > 0 {
+                                part.push(IDLRange::new(l.range, mask));
+                            }
+                            liter.next();
+                            riter.next();
+                        }
+                        Ordering::Less => { liter.next(); }
+                        Ordering::Greater => { riter.next(); }
+                    }
+                }
+                part
+            })
+            .collect();
+
+        let mut result = IDLBitRange::new();
+        result.reserve(parts.iter().map(|p| p.len()).sum());
+        for part in parts {
+            result.count += part.iter().map(|r| r.mask.count_ones() as usize).sum::<usize>();
+            result.list.extend(part);
+        }
+        result
+    }
+
+    /// True if self and other share at least one id. This stops at the
+    /// first common id, and never builds the intersection.
+    pub fn intersects(&self, other: &Self) -> bool {
+        if !self.ranges_overlap(other) {
+            return false;
+        }
+
+        let mut liter = self.list.iter();
+        let mut riter = other.list.iter();
+
+        let mut lnextrange = liter.next();
+        let mut rnextrange = riter.next();
+
+        while let (Some(l), Some(r)) = (lnextrange, rnextrange) {
+            if l.range == r.range {
+                if l.mask & r.mask != 0 {
+                    return true;
+                }
+                lnextrange = liter.next();
+                rnextrange = riter.next();
+            } else if l.range < r.range {
+                lnextrange = gallop_next(&mut liter, r.range);
+            } else {
+                rnextrange = gallop_next(&mut riter, l.range);
+            }
+        }
+        false
+    }
+
+    /// The number of ids in `self & other`, without building the result.
+    pub fn intersection_count(&self, other: &Self) -> usize {
+        let mut count = 0;
+
+        let mut liter = self.list.iter();
+        let mut riter = other.list.iter();
+
+        let mut lnextrange = liter.next();
+        let mut rnextrange = riter.next();
+
+        while let (Some(l), Some(r)) = (lnextrange, rnextrange) {
+            if l.range == r.range {
+                count += (l.mask & r.mask).count_ones() as usize;
+                lnextrange = liter.next();
+                rnextrange = riter.next();
+            } else if l.range < r.range {
+                lnextrange = liter.next();
+            } else {
+                rnextrange = riter.next();
+            }
+        }
+        count
+    }
+
+    /// The Jaccard index of the two sets, `|self & other| / |self | other|`,
+    /// computed in one pass over the ranges without building either set.
+    /// Two empty sets give `0.0`.
+    pub fn jaccard(&self, other: &IDLBitRange) -> f64 {
+        let mut inter = 0;
+        let mut union = 0;
+
+        let mut liter = self.list.iter();
+        let mut riter = other.list.iter();
+
+        let mut lnextrange = liter.next();
+        let mut rnextrange = riter.next();
+
+        while let (Some(l), Some(r)) = (lnextrange, rnextrange) {
+            if l.range == r.range {
+                inter += (l.mask & r.mask).count_ones() as usize;
+                union += (l.mask | r.mask).count_ones() as usize;
+                lnextrange = liter.next();
+                rnextrange = riter.next();
+            } else if l.range < r.range {
+                union += l.mask.count_ones() as usize;
+                lnextrange = liter.next();
+            } else {
+                union += r.mask.count_ones() as usize;
+                rnextrange = riter.next();
+            }
+        }
+        // At most one side has ranges left, and all of them are unique.
+        for rest in lnextrange.into_iter().chain(liter).chain(rnextrange).chain(riter) {
+            union += rest.mask.count_ones() as usize;
+        }
+
+        if union == 0 {
+            0.0
+        } else {
+            inter as f64 / union as f64
+        }
+    }
+
+    /// The number of ids in `self.andnot(other)`, without building the
+    /// result. Every id of self is either in other or in the difference.
+    pub fn difference_count(&self, other: &Self) -> usize {
+        self.count - self.intersection_count(other)
+    }
+
+    /// The ids of self that are not in `other`, leaving both intact. The
+    /// same as `difference`, named to pair with `AndNot::andnot`.
+    pub fn andnot_ref(&self, other: &Self) -> Self {
+        self.difference(other)
+    }
+
+    /// True if every id in this set is also in `other`.
+    pub fn is_subset_of(&self, other: &IDLBitRange) -> bool {
+        if self.count > other.count {
+            return false;
+        }
+
+        let mut riter = other.list.iter();
+        for l in &self.list {
+            // Find the matching range in other, failing if it's missing.
+            let r = loop {
+                match riter.next() {
+                    Some(r) if r.range < l.range => continue,
+                    Some(r) if r.range == l.range => break r,
+                    _ => return false,
+                }
+            };
+            if l.mask & r.mask != l.mask {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// True if every id in `other` is also in this set.
+    pub fn is_superset_of(&self, other: &IDLBitRange) -> bool {
+        other.is_subset_of(self)
+    }
+
+    /// Every id in `0..=universe_max` that is not in this set.
+    pub fn complement_within(&self, universe_max: u64) -> IDLBitRange {
+        let bmax: u64 = universe_max % 64;
+        let last_range: u64 = universe_max - bmax;
+        // Bits above universe_max must not be set in the final range.
+        let last_mask: u64 = if bmax == 63 {
+            u64::MAX
+        } else {
+            (1 << (bmax + 1)) - 1
+        };
+
+        let mut result = IDLBitRange::new();
+        let mut liter = self.list.iter().peekable();
+        let mut range: u64 = 0;
+        loop {
+            let mut mask = u64::MAX;
+            while let Some(l) = liter.peek() {
+                if l.range > range {
+                    break;
+                }
+                if l.range == range {
+                    mask = !l.mask;
+                }
+                liter.next();
+            }
+            if range == last_range {
+                mask &= last_mask;
+            }
+            if mask > 0 {
+                result.list.push(IDLRange::new(range, mask));
+                result.count += mask.count_ones() as usize;
+            }
+            if range == last_range {
+                break;
+            }
+            range += 64;
+        }
+        result
+    }
+
+    /// The ids of self within `[lo, hi]` inclusive.
+    pub fn range_query(&self, lo: u64, hi: u64) -> IDLBitRange {
+        let mut result = IDLBitRange::new();
+        for (range, mask) in self.iter_ranges_overlapping(lo, hi) {
+            result.list.push(IDLRange::new(range, mask));
+            result.count += mask.count_ones() as usize;
+        }
+        result
+    }
+
+    /// The `(base, mask)` pairs of self that hold ids within `[lo, hi]`
+    /// inclusive, without decompressing them. The masks of the ranges at
+    /// either edge are trimmed to the window, and ranges left empty by
+    /// that are skipped.
+    pub fn iter_ranges_overlapping<'a>(&'a self, lo: u64, hi: u64) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let lo_range = lo - (lo % 64);
+        let hi_range = hi - (hi % 64);
+        let lo_mask: u64 = u64::MAX << (lo % 64);
+        let hi_mask: u64 = u64::MAX >> (63 - (hi % 64));
+
+        let list = if lo > hi {
+            &self.list[..0]
+        } else {
+            let start = match self.list.binary_search_by(|r| r.range.cmp(&lo_range)) {
+                Ok(i) | Err(i) => i,
+            };
+            &self.list[start..]
+        };
+        list.iter()
+            .take_while(move |r| r.range <= hi_range)
+            .filter_map(move |r| {
+                let mut mask = r.mask;
+                if r.range == lo_range {
+                    mask &= lo_mask;
+                }
+                if r.range == hi_range {
+                    mask &= hi_mask;
+                }
+                if mask > 0 {
+                    Some((r.range, mask))
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// True if every id within `[lo, hi]` inclusive is in self. An empty
+    /// window, where `lo > hi`, is always contained.
+    pub fn contains_all_in(&self, lo: u64, hi: u64) -> bool {
+        if lo > hi {
+            return true;
+        }
+        let lo_range = lo - (lo % 64);
+        let hi_range = hi - (hi % 64);
+        let lo_mask: u64 = u64::MAX << (lo % 64);
+        let hi_mask: u64 = u64::MAX >> (63 - (hi % 64));
+
+        let start = match self.list.binary_search_by(|r| r.range.cmp(&lo_range)) {
+            Ok(i) => i,
+            Err(_) => return false,
+        };
+        // Every range of the window must be present, one after another,
+        // with all of the window's bits set.
+        let mut range = lo_range;
+        for r in &self.list[start..] {
+            if r.range != range {
+                return false;
+            }
+            let mut want = u64::MAX;
+            if range == lo_range {
+                want &= lo_mask;
+            }
+            if range == hi_range {
+                want &= hi_mask;
+            }
+            if r.mask & want != want {
+                return false;
+            }
+            if range == hi_range {
+                return true;
+            }
+            range += 64;
+        }
+        false
+    }
+
+    /// The `n` smallest ids of self.
+    pub fn take_ids(&self, n: usize) -> IDLBitRange {
+        if n >= self.count {
+            return self.clone();
+        }
+        let mut result = IDLBitRange::new();
+        for r in &self.list {
+            let want = n - result.count;
+            if want == 0 {
+                break;
+            }
+            let c = r.mask.count_ones() as usize;
+            let mask = if c <= want {
+                r.mask
+            } else {
+                // Keep only the lowest want bits of this range.
+                let mut rest = r.mask;
+                let mut mask: u64 = 0;
+                for _ in 0..want {
+                    mask |= rest & rest.wrapping_neg();
+                    rest &= rest - 1;
+                }
+                mask
+            };
+            result.list.push(IDLRange::new(r.range, mask));
+            result.count += mask.count_ones() as usize;
+        }
+        result
+    }
+
+    /// Keep only the ids for which `f` returns true. `f` is called once per
+    /// id, in ascending id order. Ranges left empty are removed.
+    pub fn retain<F: FnMut(u64) -> bool>(&mut self, mut f: F) {
+        let mut count = 0;
+        self.list.retain_mut(|r| {
+            let mut rest = r.mask;
+            while rest > 0 {
+                let bit = rest & rest.wrapping_neg();
+                if !f(r.range + bit.trailing_zeros() as u64) {
+                    r.mask &= !bit;
+                }
+                rest &= rest - 1;
+            }
+            count += r.mask.count_ones() as usize;
+            r.mask > 0
+        });
+        self.count = count;
+    }
+
+    /// Partition into the ids less than `id`, and those greater or equal.
+    pub fn split_at(&self, id: u64) -> (IDLBitRange, IDLBitRange) {
+        let bvalue: u64 = id % 64;
+        let range: u64 = id - bvalue;
+        let lower_mask: u64 = (1 << bvalue) - 1;
+
+        let (idx, straddle) = match self.list.binary_search_by(|r| r.range.cmp(&range)) {
+            Ok(i) => (i, Some(&self.list[i])),
+            Err(i) => (i, None),
+        };
+
+        let mut lower = IDLBitRange::new();
+        let mut upper = IDLBitRange::new();
+        lower.list.extend_from_slice(&self.list[..idx]);
+        if let Some(r) = straddle {
+            let lmask = r.mask & lower_mask;
+            let umask = r.mask & !lower_mask;
+            if lmask > 0 {
+                lower.list.push(IDLRange::new(r.range, lmask));
+            }
+            if umask > 0 {
+                upper.list.push(IDLRange::new(r.range, umask));
+            }
+            upper.list.extend_from_slice(&self.list[idx + 1..]);
+        } else {
+            upper.list.extend_from_slice(&self.list[idx..]);
+        }
+        lower.count = lower.list.iter().map(|r| r.mask.count_ones() as usize).sum();
+        upper.count = self.count - lower.count;
+        (lower, upper)
+    }
+
+    /// The ids strictly greater than `cursor`, for resuming a paged scan
+    /// from the last id seen.
+    pub fn ids_after(&self, cursor: u64) -> IDLBitRange {
+        let bvalue: u64 = cursor % 64;
+        let range: u64 = cursor - bvalue;
+        // The bits above the cursor. When it is the top bit of its range,
+        // nothing in that range is kept.
+        let upper_mask: u64 = if bvalue == 63 { 0 } else { u64::MAX << (bvalue + 1) };
+
+        let mut result = IDLBitRange::new();
+        let idx = match self.list.binary_search_by(|r| r.range.cmp(&range)) {
+            Ok(i) => {
+                let mask = self.list[i].mask & upper_mask;
+                if mask > 0 {
+                    result.list.push(IDLRange::new(range, mask));
+                }
+                i + 1
+            }
+            Err(i) => i,
+        };
+        result.list.extend_from_slice(&self.list[idx..]);
+        result.count = result.list.iter().map(|r| r.mask.count_ones() as usize).sum();
+        result
+    }
+
+    /// The ids strictly less than `cursor`, for paging backwards from the
+    /// first id seen.
+    pub fn ids_before(&self, cursor: u64) -> IDLBitRange {
+        let bvalue: u64 = cursor % 64;
+        let range: u64 = cursor - bvalue;
+        let lower_mask: u64 = (1 << bvalue) - 1;
+
+        let mut result = IDLBitRange::new();
+        match self.list.binary_search_by(|r| r.range.cmp(&range)) {
+            Ok(i) => {
+                result.list.extend_from_slice(&self.list[..i]);
+                let mask = self.list[i].mask & lower_mask;
+                if mask > 0 {
+                    result.list.push(IDLRange::new(range, mask));
+                }
+            }
+            Err(i) => result.list.extend_from_slice(&self.list[..i]),
+        }
+        result.count = result.list.iter().map(|r| r.mask.count_ones() as usize).sum();
+        result
+    }
+
+    /// Union many sets in a single pass. This merges the ranges of all inputs
+    /// at once rather than building an intermediate result per `|`.
+    pub fn union_all<I: IntoIterator<Item = IDLBitRange>>(sets: I) -> IDLBitRange {
+        let sets: Vec<IDLBitRange> = sets.into_iter().collect();
+        let mut result = IDLBitRange::new();
+
+        // Min-heap of the next (range, set, position) from each input.
+        let mut heads = BinaryHeap::with_capacity(sets.len());
+        for (i, set) in sets.iter().enumerate() {
+            if let Some(r) = set.list.first() {
+                heads.push(Reverse((r.range, i, 0)));
+            }
+        }
+
+        while let Some(Reverse((range, i, pos))) = heads.pop() {
+            let mask = sets[i].list[pos].mask;
+            match result.list.last_mut() {
+                Some(last) if last.range == range => {
+                    result.count += (mask & !last.mask).count_ones() as usize;
+                    last.mask |= mask;
+                }
+                _ => {
+                    result.list.push(IDLRange::new(range, mask));
+                    result.count += mask.count_ones() as usize;
+                }
+            }
+            if let Some(r) = sets[i].list.get(pos + 1) {
+                heads.push(Reverse((r.range, i, pos + 1)));
+            }
+        }
+        result
+    }
+
+    /// Intersect many sets. The inputs are processed from smallest to
+    /// largest, and processing stops as soon as the result is empty. An
+    /// empty term stops it before any later terms are even taken from
+    /// `sets`. An empty input yields an empty set.
+    pub fn intersection_all<I: IntoIterator<Item = IDLBitRange>>(sets: I) -> IDLBitRange {
+        let mut sorted: Vec<IDLBitRange> = Vec::new();
+        for s in sets {
+            if s.is_empty() {
+                return IDLBitRange::new();
+            }
+            sorted.push(s);
+        }
+        sorted.sort_by_key(|s| s.len());
+
+        let mut siter = sorted.into_iter();
+        let mut result = match siter.next() {
+            Some(s) => s,
+            None => return IDLBitRange::new(),
+        };
+        for s in siter {
+            if result.is_empty() {
+                break;
+            }
+            result &= s;
+        }
+        result
+    }
+
+    /// The smallest id in the set, or `None` if it is empty.
+    #[deprecated(note = "use first; on an owned set .min() resolves to Ord::min")]
+    pub fn min(&self) -> Option<u64> {
+        self.first()
+    }
+
+    /// The largest id in the set, or `None` if it is empty.
+    #[deprecated(note = "use last; on an owned set .max() resolves to Ord::max")]
+    pub fn max(&self) -> Option<u64> {
+        self.last()
+    }
+
+    /// The first id in the set, or `None` if it is empty. As the set is
+    /// ordered this is also the smallest.
+    pub fn first(&self) -> Option<u64> {
+        self.list.first().map(|r| r.range + r.mask.trailing_zeros() as u64)
+    }
+
+    /// The last id in the set, or `None` if it is empty. As the set is
+    /// ordered this is also the largest.
+    pub fn last(&self) -> Option<u64> {
+        self.list.last().map(|r| r.range + 63 - r.mask.leading_zeros() as u64)
+    }
+
+    /// The number of ids in the set that are strictly less than `id`.
+    pub fn rank(&self, id: u64) -> usize {
+        let bvalue: u64 = id % 64;
+        let range: u64 = id - bvalue;
+
+        let mut count = 0;
+        for r in &self.list {
+            if r.range < range {
+                count += r.mask.count_ones() as usize;
+            } else {
+                if r.range == range {
+                    // When bvalue is 0 there are no lower bits to count, and
+                    // 1 << 0 - 1 gives the empty mask we want.
+                    let lower: u64 = (1 << bvalue) - 1;
+                    count += (r.mask & lower).count_ones() as usize;
+                }
+                break;
+            }
+        }
+        count
+    }
+
+    /// The `n`th smallest id in the set, counting from 0.
+    pub fn select(&self, n: usize) -> Option<u64> {
+        if n >= self.count {
+            return None;
+        }
+
+        let mut remaining = n;
+        for r in &self.list {
+            let ones = r.mask.count_ones() as usize;
+            if remaining < ones {
+                // Clear the lower set bits until ours is the lowest.
+                let mut mask = r.mask;
+                for _ in 0..remaining {
+                    mask &= mask - 1;
+                }
+                return Some(r.range + mask.trailing_zeros() as u64);
+            }
+            remaining -= ones;
+        }
+        None
+    }
+
+    /// Encode the compressed ranges as bytes. The layout is a little endian
+    /// u64 count of ranges, followed by each range as a little endian u64
+    /// base and u64 mask.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(8 + self.list.len() * 16);
+        buf.extend_from_slice(&(self.list.len() as u64).to_le_bytes());
+        for r in &self.list {
+            buf.extend_from_slice(&r.range.to_le_bytes());
+            buf.extend_from_slice(&r.mask.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Decode bytes produced by `to_bytes`. The ranges must be strictly
+    /// ascending with non-zero masks, and the buffer must hold exactly the
+    /// ranges its count gives, else an error is returned.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, IDLError> {
+        fn read_u64(buf: &[u8], offset: usize) -> u64 {
+            let mut b = [0; 8];
+            b.copy_from_slice(&buf[offset..offset + 8]);
+            u64::from_le_bytes(b)
+        }
+
+        // Anything but whole ranges after the count means the buffer was cut
+        // short, or was never ours.
+        if buf.len() < 8 || (buf.len() - 8) % 16 != 0 {
+            return Err(IDLError::Truncated);
+        }
+        let count = read_u64(buf, 0) as usize;
+        match count.checked_mul(16).and_then(|l| l.checked_add(8)) {
+            Some(l) if l == buf.len() => {}
+            _ => return Err(IDLError::Truncated),
+        }
+
+        IDLBitRange::from_ranges(
+            (0..count).map(|i| (read_u64(buf, 8 + i * 16), read_u64(buf, 16 + i * 16)))
+        )
+    }
+}
+
+// Empty ranges are never stored, so equal sets always have identical range
+// lists and hash the same.
+impl Hash for IDLBitRange {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.list.len().hash(state);
+        for r in &self.list {
+            r.range.hash(state);
+            r.mask.hash(state);
+        }
+    }
+}
+
+// Sets are ordered as their ascending id sequences are, lexicographically,
+// so a set sorts before any longer set it is a prefix of. This works on the
+// ranges rather than decompressing them.
+impl Ord for IDLBitRange {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let mut liter = self.list.iter();
+        let mut riter = other.list.iter();
+        loop {
+            let (l, r) = match (liter.next(), riter.next()) {
+                (None, None) => return Ordering::Equal,
+                (None, Some(_)) => return Ordering::Less,
+                (Some(_), None) => return Ordering::Greater,
+                (Some(l), Some(r)) => (l, r),
+            };
+            if l.range != r.range {
+                // The lower range holds the lower next id.
+                return l.range.cmp(&r.range);
+            }
+            if l.mask == r.mask {
+                continue;
+            }
+            // The ids below the first differing bit match. The side with
+            // that bit is smaller, unless the other side has no more ids,
+            // in which case it is a prefix of this one.
+            let d = (l.mask ^ r.mask).trailing_zeros();
+            return if l.mask & (1 << d) != 0 {
+                if r.mask >> d != 0 || riter.len() > 0 {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                }
+            } else if l.mask >> d != 0 || liter.len() > 0 {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+    }
+}
+
+impl PartialOrd for IDLBitRange {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(feature = "simd")]
+mod simd {
+    use super::{IDLBitRange, IDLRange};
+
+    /// AND runs of ranges with pairwise equal keys into result. Because
+    /// the keys are equal, ANDing a whole `IDLRange` leaves the key as is.
+    pub(crate) fn and_ranges(l: &[IDLRange], r: &[IDLRange], result: &mut IDLBitRange) {
+        debug_assert_eq!(l.len(), r.len());
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                unsafe { and_ranges_avx2(l, r, result) };
+                return;
+            }
+        }
+        and_ranges_scalar(l, r, result)
+    }
+
+    pub(crate) fn and_ranges_scalar(l: &[IDLRange], r: &[IDLRange], result: &mut IDLBitRange) {
+        for (a, b) in l.iter().zip(r.iter()) {
+            push_mask(result, a.range, a.mask & b.mask);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    pub(crate) unsafe fn and_ranges_avx2(l: &[IDLRange], r: &[IDLRange], result: &mut IDLBitRange) {
+        use std::arch::x86_64::{__m256i, _mm256_and_si256, _mm256_loadu_si256, _mm256_storeu_si256};
+
+        // Two 16 byte IDLRanges per 256 bit lane.
+        let pairs = l.len() / 2;
+        let mut out = [0u64; 4];
+        for i in 0..pairs {
+            let a = _mm256_loadu_si256(l.as_ptr().add(i * 2) as *const __m256i);
+            let b = _mm256_loadu_si256(r.as_ptr().add(i * 2) as *const __m256i);
+            _mm256_storeu_si256(out.as_mut_ptr() as *mut __m256i, _mm256_and_si256(a, b));
+            push_mask(result, out[0], out[1]);
+            push_mask(result, out[2], out[3]);
+        }
+        and_ranges_scalar(&l[pairs * 2..], &r[pairs * 2..], result);
+    }
+
+    #[inline(always)]
+    fn push_mask(result: &mut IDLBitRange, range: u64, mask: u64) {
+        if mask > 0 {
+            result.list.push(IDLRange::new(range, mask));
+            result.count += mask.count_ones() as usize;
+        }
+    }
+}
+
+// As partition_point, the index of the first element of list that fails
+// pred. This probes at doubling distances first, so finding a nearby
+// element stays cheap.
+fn gallop<T, F: Fn(&T) -> bool>(list: &[T], pred: F) -> usize {
+    let mut lo = 0;
+    let mut step = 1;
+    while step <= list.len() && pred(&list[step - 1]) {
+        lo = step;
+        step *= 2;
+    }
+    let hi = step.min(list.len());
+    lo + list[lo..hi].partition_point(pred)
+}
+
+// Skip iter past the ranges below range, returning the next one.
+fn gallop_next<'a>(iter: &mut slice::Iter<'a, IDLRange>, range: u64) -> Option<&'a IDLRange> {
+    let rest = iter.as_slice();
+    *iter = rest[gallop(rest, |x| x.range < range)..].iter();
+    iter.next()
+}
+
+impl Default for IDLBitRange {
+    fn default() -> Self {
+        IDLBitRange::new()
+    }
+}
+
+impl IDL for IDLBitRange {
+    fn push_id(&mut self, value: u64) {
+        // Get what range this should be
+        let bvalue: u64 = value % 64;
+        let range: u64 = value - bvalue;
+
+        // Get the highest IDLRange out. Most inserts are ascending, so
+        // this avoids the search in the common case.
+        let idx = match self.list.last_mut() {
+            Some(last) if last.range == range => {
+                // Insert the bit.
+                if last.push_id(bvalue) {
+                    self.count += 1;
+                }
+                return;
+            }
+            Some(last) if last.range > range => {
+                // Out of order, find where this id belongs.
+                match self.list.binary_search_by(|r| r.range.cmp(&range)) {
+                    Ok(idx) => {
+                        if self.list[idx].push_id(bvalue) {
+                            self.count += 1;
+                        }
+                        return;
+                    }
+                    Err(idx) => idx,
+                }
+            }
+            _ => self.list.len(),
+        };
+
+        // New takes a starting mask, not a raw bval, so shift it!
+        let newrange = IDLRange::new(range, 1 << bvalue);
+        self.list.insert(idx, newrange);
+        self.count += 1;
+    }
+
+    fn push_ids(&mut self, ids: &[u64]) {
+        // Build the ranges of a sorted batch in one pass and merge them in,
+        // rather than finding the range again for every id.
+        if ids.windows(2).all(|w| w[0] < w[1]) {
+            self.union_into(&IDLBitRange::from_sorted_slice(ids));
+        } else {
+            for id in ids {
+                self.push_id(*id);
+            }
+        }
+    }
+
+    fn remove_id(&mut self, value: u64) {
+        let bvalue: u64 = value % 64;
+        let range: u64 = value - bvalue;
+
+        if let Ok(idx) = self.list.binary_search_by(|r| r.range.cmp(&range)) {
+            if self.list[idx].remove_id(bvalue) {
+                self.count -= 1;
+            }
+            // Never leave an empty range behind.
+            if self.list[idx].mask == 0 {
+                self.list.remove(idx);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.count
+    }
+
+    fn is_empty(&self) -> bool {
+        // Empty ranges are never retained, so no ranges means no ids.
+        self.list.is_empty()
+    }
+
+    fn contains(&self, id: u64) -> bool {
+        let bvalue: u64 = id % 64;
+        let range: u64 = id - bvalue;
+
+        match self.list.binary_search_by(|r| r.range.cmp(&range)) {
+            Ok(idx) => self.list[idx].mask & (1 << bvalue) > 0,
+            Err(_) => false,
+        }
+    }
+}
+
+impl FromIterator<u64> for IDLBitRange {
+    fn from_iter<I: IntoIterator<Item=u64>>(iter: I) -> Self {
+        let mut new = IDLBitRange {
+            list: Vec::new(),
+            count: 0,
+        };
+        for i in iter {
+            new.push_id(i);
+        }
+        new
+    }
+}
+
+impl FromIterator<IDLBitRange> for IDLBitRange {
+    fn from_iter<I: IntoIterator<Item=IDLBitRange>>(iter: I) -> Self {
+        IDLBitRange::union_all(iter)
+    }
+}
+
+impl Extend<u64> for IDLBitRange {
+    fn extend<I: IntoIterator<Item=u64>>(&mut self, iter: I) {
+        // Ascending input stays on the push_id fast path.
+        for i in iter {
+            self.push_id(i);
+        }
+    }
+}
+
+impl Extend<IDLBitRange> for IDLBitRange {
+    fn extend<I: IntoIterator<Item=IDLBitRange>>(&mut self, iter: I) {
+        for other in iter {
+            self.union_into(&other);
+        }
+    }
+}
+
+impl BitAnd for IDLBitRange
+{
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        self.intersection(&rhs)
+    }
+}
+
+impl BitOr for IDLBitRange
+{
+    type Output = Self;
+
+    fn bitor(mut self, mut rhs: Self) -> Self {
+        // We own both, so grow whichever is larger.
+        if self.list.len() >= rhs.list.len() {
+            self.union_into(&rhs);
+            self
+        } else {
+            rhs.union_into(&self);
+            rhs
+        }
+    }
+}
+
+impl BitXor for IDLBitRange
+{
+    type Output = Self;
+
+    fn bitxor(self, rhs: Self) -> Self {
+        let mut result = IDLBitRange::new();
+        result.reserve(self.list.len().max(rhs.list.len()));
+
+        let mut liter = self.list.iter();
+        let mut riter = rhs.list.iter();
+
+        let mut lnextrange = liter.next();
+        let mut rnextrange = riter.next();
+
+        while lnextrange.is_some() && rnextrange.is_some() {
+            let l = lnextrange.unwrap();
+            let r = rnextrange.unwrap();
+
+            let (range, mask) = if l.range == r.range {
+                lnextrange = liter.next();
+                rnextrange = riter.next();
+                (l.range, l.mask ^ r.mask)
+            } else if l.range < r.range {
+                lnextrange = liter.next();
+                (l.range, l.mask)
+            } else {
+                rnextrange = riter.next();
+                (r.range, r.mask)
+            };
+            // Identical ranges cancel out entirely, don't keep them.
+            if mask > 0 {
+                let newrange = IDLRange::new(range, mask);
+                result.list.push(newrange);
+                result.count += mask.count_ones() as usize;
+            }
+        }
+
+        while lnextrange.is_some() {
+            let l = lnextrange.unwrap();
+
+            let newrange = IDLRange::new(l.range, l.mask);
+            result.list.push(newrange);
+            result.count += l.mask.count_ones() as usize;
+            lnextrange = liter.next();
+        }
+
+        while rnextrange.is_some() {
+            let r = rnextrange.unwrap();
+
+            let newrange = IDLRange::new(r.range, r.mask);
+            result.list.push(newrange);
+            result.count += r.mask.count_ones() as usize;
+            rnextrange = riter.next();
+        }
+        result
+    }
+}
+
+impl AndNot for IDLBitRange {
+    type Output = Self;
+
+    fn andnot(self, rhs: Self) -> Self {
+        self.difference(&rhs)
+    }
+}
+
+impl<'a> BitAnd<&'a IDLBitRange> for &'a IDLBitRange {
+    type Output = IDLBitRange;
+
+    fn bitand(self, rhs: &'a IDLBitRange) -> IDLBitRange {
+        self.intersection(rhs)
+    }
+}
+
+impl<'a> BitOr<&'a IDLBitRange> for &'a IDLBitRange {
+    type Output = IDLBitRange;
+
+    fn bitor(self, rhs: &'a IDLBitRange) -> IDLBitRange {
+        self.union(rhs)
+    }
+}
+
+impl<'a> AndNot<&'a IDLBitRange> for &'a IDLBitRange {
+    type Output = IDLBitRange;
+
+    fn andnot(self, rhs: &'a IDLBitRange) -> IDLBitRange {
+        self.difference(rhs)
+    }
+}
+
+impl BitAndAssign for IDLBitRange {
+    fn bitand_assign(&mut self, rhs: Self) {
+        // An intersection can only shrink, so filter our ranges in place.
+        let mut riter = rhs.list.iter();
+        let mut rnextrange = riter.next();
+        let mut count = 0;
+
+        self.list.retain_mut(|l| {
+            while let Some(r) = rnextrange {
+                if r.range >= l.range {
+                    break;
+                }
+                rnextrange = riter.next();
+            }
+            match rnextrange {
+                Some(r) if r.range == l.range => {
+                    l.mask &= r.mask;
+                    count += l.mask.count_ones() as usize;
+                    l.mask > 0
+                }
+                _ => false,
+            }
+        });
+        self.count = count;
+    }
+}
+
+impl BitOrAssign for IDLBitRange {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.union_into(&rhs);
+    }
+}
+
+impl AndNotAssign for IDLBitRange {
+    fn andnot_assign(&mut self, rhs: Self) {
+        // As with &=, this can only shrink so is done in place.
+        let mut riter = rhs.list.iter();
+        let mut rnextrange = riter.next();
+        let mut count = 0;
+
+        self.list.retain_mut(|l| {
+            while let Some(r) = rnextrange {
+                if r.range >= l.range {
+                    break;
+                }
+                rnextrange = riter.next();
+            }
+            if let Some(r) = rnextrange {
+                if r.range == l.range {
+                    l.mask &= !r.mask;
+                }
+            }
+            count += l.mask.count_ones() as usize;
+            l.mask > 0
+        });
+        self.count = count;
+    }
+}
+
+// Decompresses ranges into ids from either end. This is shared by the
+// borrowing and owning iterators, which differ only in where the ranges
+// come from.
+#[derive(Debug)]
+struct RangeIds<I> {
+    rangeiter: I,
+    // The ranges currently being consumed from either end. Bits are
+    // cleared from these as they are yielded.
+    front: Option<IDLRange>,
+    back: Option<IDLRange>,
+    // Ids not yet yielded from either end.
+    remaining: usize,
+}
+
+impl<I: DoubleEndedIterator<Item = IDLRange>> RangeIds<I> {
+    fn new(rangeiter: I, remaining: usize) -> Self {
+        RangeIds {
+            rangeiter,
+            front: None,
+            back: None,
+            remaining,
+        }
+    }
+
+    fn next(&mut self) -> Option<u64> {
+        // Once nth has skipped to the end, rangeiter may still hold ranges.
+        if self.remaining == 0 {
+            return None;
+        }
+        loop {
+            if let Some(id) = self.front.as_mut().and_then(|r| r.pop_first()) {
+                self.remaining -= 1;
+                return Some(id);
+            }
+            match self.rangeiter.next() {
+                Some(r) => self.front = Some(r),
+                // Only the back range can have anything left.
+                None => {
+                    let id = self.back.as_mut().and_then(|r| r.pop_first());
+                    if id.is_some() {
+                        self.remaining -= 1;
+                    }
+                    return id;
+                }
+            }
+        }
+    }
+
+    fn nth(&mut self, mut n: usize) -> Option<u64> {
+        if n >= self.remaining {
+            self.remaining = 0;
+            self.front = None;
+            self.back = None;
+            return None;
+        }
+        // Skip whole ranges by their popcount, rather than bit by bit.
+        let mut front = self.front.take();
+        loop {
+            if let Some(ref f) = front {
+                let c = f.mask.count_ones() as usize;
+                if n < c {
+                    break;
+                }
+                n -= c;
+                self.remaining -= c;
+            }
+            front = self.rangeiter.next();
+            if front.is_none() {
+                break;
+            }
+        }
+        self.front = front;
+        // n now falls within the front range, or the back if none remain.
+        let target = if self.front.is_some() { &mut self.front } else { &mut self.back };
+        if let Some(ref mut r) = *target {
+            for _ in 0..n {
+                r.mask &= r.mask - 1;
+            }
+            self.remaining -= n;
+        }
+        self.next()
+    }
+
+    fn next_back(&mut self) -> Option<u64> {
+        if self.remaining == 0 {
+            return None;
+        }
+        loop {
+            if let Some(id) = self.back.as_mut().and_then(|r| r.pop_last()) {
+                self.remaining -= 1;
+                return Some(id);
+            }
+            match self.rangeiter.next_back() {
+                Some(r) => self.back = Some(r),
+                // Only the front range can have anything left.
+                None => {
+                    let id = self.front.as_mut().and_then(|r| r.pop_last());
+                    if id.is_some() {
+                        self.remaining -= 1;
+                    }
+                    return id;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct IDLBitRangeIter<'a> {
+    inner: RangeIds<iter::Cloned<slice::Iter<'a, IDLRange>>>,
+}
+
+impl<'a>Iterator for IDLBitRangeIter<'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.inner.remaining, Some(self.inner.remaining))
+    }
+
+    fn nth(&mut self, n: usize) -> Option<u64> {
+        self.inner.nth(n)
+    }
+}
+
+impl<'a> DoubleEndedIterator for IDLBitRangeIter<'a> {
+    fn next_back(&mut self) -> Option<u64> {
+        self.inner.next_back()
+    }
+}
+
+impl<'a> ExactSizeIterator for IDLBitRangeIter<'a> {}
+
+impl<'a> IntoIterator for &'a IDLBitRange {
+    type Item = u64;
+    type IntoIter = IDLBitRangeIter<'a>;
+
+    fn into_iter(self) -> IDLBitRangeIter<'a> {
+        IDLBitRangeIter {
+            inner: RangeIds::new(self.list.iter().cloned(), self.count),
+        }
+    }
+}
+
+/// Yields the ids of an `IDLBitRange` it has taken ownership of.
+#[derive(Debug)]
+pub struct IDLBitRangeIntoIter {
+    inner: RangeIds<vec::IntoIter<IDLRange>>,
+}
+
+impl Iterator for IDLBitRangeIntoIter {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.inner.remaining, Some(self.inner.remaining))
+    }
+
+    fn nth(&mut self, n: usize) -> Option<u64> {
+        self.inner.nth(n)
+    }
+}
+
+impl DoubleEndedIterator for IDLBitRangeIntoIter {
+    fn next_back(&mut self) -> Option<u64> {
+        self.inner.next_back()
+    }
+}
+
+impl ExactSizeIterator for IDLBitRangeIntoIter {}
+
+impl IntoIterator for IDLBitRange {
+    type Item = u64;
+    type IntoIter = IDLBitRangeIntoIter;
+
+    fn into_iter(self) -> IDLBitRangeIntoIter {
+        IDLBitRangeIntoIter {
+            inner: RangeIds::new(self.list.into_iter(), self.count),
+        }
+    }
+}
+
+impl fmt::Debug for IDLBitRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IDLBitRange (compressed) {:?} (decompressed) [ ", self.list).unwrap();
+        for id in self {
+            write!(f, "{}, ", id).unwrap();
+        }
+        write!(f, "]")
+    }
+}
+
+impl From<IDLSimple> for IDLBitRange {
+    fn from(idl: IDLSimple) -> Self {
+        // push_id keeps this valid even if the simple list is out of order.
+        IDLBitRange::from_iter(idl.0)
+    }
+}
+
+impl From<IDLBitRange> for IDLSimple {
+    fn from(idl: IDLBitRange) -> Self {
+        IDLSimple::from_iter(&idl)
+    }
+}
+
+// A compressed range costs two u64s, so it is smaller than the simple form
+// once it holds more than this many ids on average.
+const IDL_COMPRESS_THRESHOLD: usize = 2;
+
+/// An IDL that chooses between the simple and compressed representations
+/// based on how dense the ids are. Scattered ids waste most of a range's
+/// mask, so they are kept as an `IDLSimple`, while dense ids are kept as
+/// an `IDLBitRange`.
+///
+/// Equality compares the ids, so the same ids compare equal whichever form
+/// holds them.
+#[derive(Debug, Clone)]
+pub enum IDLType {
+    Simple(IDLSimple),
+    Compressed(IDLBitRange),
+}
+
+impl IDLType {
+    pub fn new() -> Self {
+        IDLType::Simple(IDLSimple::new())
+    }
+
+    fn should_compress(&self) -> bool {
+        match *self {
+            IDLType::Simple(ref idl) => {
+                // Count the distinct ranges the ids fall in.
+                let mut ranges = 0;
+                let mut last_range = None;
+                for id in &idl.0 {
+                    let range = Some(id - (id % 64));
+                    if range != last_range {
+                        ranges += 1;
+                        last_range = range;
+                    }
+                }
+                idl.len() > ranges * IDL_COMPRESS_THRESHOLD
+            }
+            IDLType::Compressed(ref idl) => {
+                idl.len() > idl.list.len() * IDL_COMPRESS_THRESHOLD
+            }
+        }
+    }
+
+    fn into_compressed(self) -> IDLBitRange {
+        match self {
+            IDLType::Simple(idl) => IDLBitRange::from(idl),
+            IDLType::Compressed(idl) => idl,
+        }
+    }
+
+    /// Re-evaluate the density of the ids, and convert to the smaller
+    /// representation in place if needed.
+    pub fn optimize(&mut self) {
+        let compress = self.should_compress();
+        let new = match *self {
+            IDLType::Simple(ref idl) if compress => {
+                IDLType::Compressed(IDLBitRange::from_sorted_unchecked(idl.0.iter().cloned()))
+            }
+            IDLType::Compressed(ref idl) if !compress => {
+                IDLType::Simple(IDLSimple::from_iter(idl))
+            }
+            _ => return,
+        };
+        *self = new;
+    }
+}
+
+impl PartialEq for IDLType {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (IDLType::Simple(l), IDLType::Simple(r)) => l == r,
+            (IDLType::Compressed(l), IDLType::Compressed(r)) => l == r,
+            (IDLType::Simple(s), IDLType::Compressed(c))
+            | (IDLType::Compressed(c), IDLType::Simple(s)) => {
+                s.len() == c.len() && s.into_iter().eq(c)
+            }
+        }
+    }
+}
+
+impl Default for IDLType {
+    fn default() -> Self {
+        IDLType::new()
+    }
+}
+
+impl IDL for IDLType {
+    fn push_id(&mut self, value: u64) {
+        match *self {
+            IDLType::Simple(ref mut idl) => idl.push_id(value),
+            IDLType::Compressed(ref mut idl) => idl.push_id(value),
+        }
+        // Checking density is a walk for simple, so only do it as the
+        // size doubles to keep pushes amortised constant.
+        if self.len().is_power_of_two() {
+            self.optimize();
+        }
+    }
+
+    fn remove_id(&mut self, value: u64) {
+        match *self {
+            IDLType::Simple(ref mut idl) => idl.remove_id(value),
+            IDLType::Compressed(ref mut idl) => idl.remove_id(value),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            IDLType::Simple(ref idl) => idl.len(),
+            IDLType::Compressed(ref idl) => idl.len(),
+        }
+    }
+
+    fn contains(&self, id: u64) -> bool {
+        match *self {
+            IDLType::Simple(ref idl) => idl.contains(id),
+            IDLType::Compressed(ref idl) => idl.contains(id),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match *self {
+            IDLType::Simple(ref idl) => idl.is_empty(),
+            IDLType::Compressed(ref idl) => idl.is_empty(),
+        }
+    }
+}
+
+impl FromIterator<u64> for IDLType {
+    fn from_iter<I: IntoIterator<Item=u64>>(iter: I) -> Self {
+        let mut new = IDLType::Simple(IDLSimple::from_iter(iter));
+        new.optimize();
+        new
+    }
+}
+
+impl BitAnd for IDLType
+{
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        let mut result = match (self, rhs) {
+            (IDLType::Simple(l), IDLType::Simple(r)) => IDLType::Simple(l & r),
+            (l, r) => IDLType::Compressed(l.into_compressed() & r.into_compressed()),
+        };
+        result.optimize();
+        result
+    }
+}
+
+impl BitOr for IDLType
+{
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        let mut result = match (self, rhs) {
+            (IDLType::Simple(l), IDLType::Simple(r)) => IDLType::Simple(l | r),
+            (l, r) => IDLType::Compressed(l.into_compressed() | r.into_compressed()),
+        };
+        result.optimize();
+        result
+    }
+}
+
+impl AndNot for IDLType {
+    type Output = Self;
+
+    fn andnot(self, rhs: Self) -> Self {
+        let mut result = match (self, rhs) {
+            (IDLType::Simple(l), IDLType::Simple(r)) => IDLType::Simple(l.andnot(r)),
+            (l, r) => IDLType::Compressed(l.into_compressed().andnot(r.into_compressed())),
+        };
+        result.optimize();
+        result
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for IDLSimple {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for IDLSimple {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Route through push_id so that we are sorted and unique.
+        let list: Vec<u64> = Vec::deserialize(deserializer)?;
+        Ok(IDLSimple::from_iter(list))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for IDLBitRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Write the compressed (range, mask) pairs, not the ids, so that
+        // dense sets stay small.
+        let mut seq = serializer.serialize_seq(Some(self.list.len()))?;
+        for r in &self.list {
+            seq.serialize_element(&(r.range, r.mask))?;
+        }
+        seq.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for IDLBitRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pairs: Vec<(u64, u64)> = Vec::deserialize(deserializer)?;
+        IDLBitRange::from_ranges(pairs).map_err(D::Error::custom)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    // use test::Bencher;
+    use super::{IDL, IDLSimple, IDLBitRange, IDLRange, IDLError, IDLType, AndNot, AndNotAssign};
+    use std::iter::FromIterator;
+    use std::collections::HashMap;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    // xorshift, so the inputs are random but reproducible.
+    fn xorshift(mut seed: u64) -> impl FnMut() -> u64 {
+        move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        }
+    }
+
+    #[test]
+    fn test_simple_intersection_1() {
+        let idl_a = IDLSimple::from_iter(vec![1, 2, 3]);
+        let idl_b = IDLSimple::from_iter(vec![2]);
+        let idl_expect = IDLSimple::from_iter(vec![2]);
+
+        let idl_result = idl_a & idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_intersection_1() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3]);
+        let idl_b = IDLBitRange::from_iter(vec![2]);
         let idl_expect = IDLBitRange::from_iter(vec![2]);
 
         let idl_result = idl_a & idl_b;
@@ -573,214 +2526,1992 @@ mod tests {
     }
 
     #[test]
-    fn test_simple_intersection_2() {
-        let idl_a = IDLSimple::from_iter(vec![1, 2, 3]);
-        let idl_b = IDLSimple::from_iter(vec![4, 67]);
-        let idl_expect = IDLSimple::new();
-
-        let idl_result = idl_a & idl_b;
+    fn test_simple_intersection_2() {
+        let idl_a = IDLSimple::from_iter(vec![1, 2, 3]);
+        let idl_b = IDLSimple::from_iter(vec![4, 67]);
+        let idl_expect = IDLSimple::new();
+
+        let idl_result = idl_a & idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_intersection_2() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3]);
+        let idl_b = IDLBitRange::from_iter(vec![4, 67]);
+        let idl_expect = IDLBitRange::new();
+
+
+        let idl_result = idl_a & idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_intersection_3() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 4, 35, 64, 65, 128, 150]);
+        let idl_b = IDLBitRange::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180]);
+        let idl_expect = IDLBitRange::from_iter(vec![2, 3, 35, 64, 128, 150]);
+
+        let idl_result = idl_a & idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_simple_intersection_4() {
+        let idl_a = IDLSimple::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
+        let idl_b = IDLSimple::from_iter(1..1024);
+        let idl_expect = IDLSimple::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
+
+        let idl_result = idl_a & idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_intersection_4() {
+        let idl_a = IDLBitRange::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
+        let idl_b = IDLBitRange::from_iter(1..1024);
+        let idl_expect = IDLBitRange::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
+
+        let idl_result = idl_a & idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_simple_intersection_5() {
+        let idl_a = IDLSimple::from_iter(1..204800);
+        let idl_b = IDLSimple::from_iter(102400..307200);
+        let idl_expect = IDLSimple::from_iter(102400..204800);
+
+        let idl_result = idl_a & idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_simple_intersection_search() {
+        // The search and the merge must agree, whichever intersection picks.
+        let idl_a = IDLSimple::from_iter(1..204800);
+        let idl_b = IDLSimple::from_iter(102400..307200);
+        let idl_expect = IDLSimple::from_iter(102400..204800);
+        assert_eq!(idl_a.small_intersect(&idl_b), idl_expect);
+        assert_eq!(idl_b.small_intersect(&idl_a), idl_expect);
+        assert_eq!(idl_a.merge_intersection(&idl_b), idl_expect);
+
+        // A small set against a large one takes the search path.
+        let idl_small = IDLSimple::from_iter(vec![0, 5, 1000, 150000, 204799, 204800, 400000]);
+        let idl_expect = IDLSimple::from_iter(vec![5, 1000, 150000, 204799]);
+        assert_eq!(idl_small.merge_intersection(&idl_a), idl_expect);
+        assert_eq!(idl_small.small_intersect(&idl_a), idl_expect);
+        assert_eq!(idl_small.clone() & idl_a.clone(), idl_expect);
+        assert_eq!(idl_a & idl_small, idl_expect);
+
+        let idl_empty = IDLSimple::new();
+        assert_eq!(idl_empty.clone() & idl_b, idl_empty);
+    }
+
+    #[test]
+    fn test_simple_small_intersect_differential() {
+        let mut next = xorshift(0x9e3779b97f4a7c15);
+        let idl_large = IDLSimple::from_iter((0..20000).map(|i| i * 3));
+        for size in &[0, 1, 2, 5, 40, 300, 5000] {
+            for _ in 0..20 {
+                let idl_small = IDLSimple::from_iter((0..*size).map(|_| next() % 70000));
+                let idl_expect = idl_small.merge_intersection(&idl_large);
+                assert_eq!(idl_small.small_intersect(&idl_large), idl_expect);
+                assert_eq!(idl_large.small_intersect(&idl_small), idl_expect);
+                assert_eq!(idl_small.intersection(&idl_large), idl_expect);
+                assert_eq!(idl_large.intersection(&idl_small), idl_expect);
+            }
+        }
+    }
+
+    #[test]
+    fn test_range_intersection_5() {
+        let idl_a = IDLBitRange::from_iter(1..204800);
+        let idl_b = IDLBitRange::from_iter(102400..307200);
+        let idl_expect = IDLBitRange::from_iter(102400..204800);
+
+        let idl_result = idl_a & idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_range_par_intersection() {
+        let idl_a = IDLBitRange::from_iter(1..204800);
+        let idl_b = IDLBitRange::from_iter(102400..307200);
+        let idl_expect = IDLBitRange::from_iter(102400..204800);
+
+        let idl_result = idl_a.par_intersection(&idl_b);
+        assert_eq!(idl_result, idl_expect);
+        assert_eq!(idl_result, idl_a & idl_b);
+
+        let idl_a = IDLBitRange::from_iter((0..400000).map(|i| i * 3));
+        let idl_b = IDLBitRange::from_iter((0..600000).map(|i| i * 2));
+        let idl_result = idl_a.par_intersection(&idl_b);
+        assert_eq!(idl_result.len(), idl_a.intersection_count(&idl_b));
+        assert_eq!(idl_result, idl_a & idl_b);
+    }
+
+    #[cfg(feature = "simd")]
+    #[test]
+    fn test_range_intersection_simd_differential() {
+        let mut next = xorshift(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..64 {
+            let n = (next() % 512) as usize;
+            let a: Vec<u64> = (0..n * 40).map(|_| next() % (n as u64 * 64 + 64)).collect();
+            let b: Vec<u64> = (0..n * 40).map(|_| next() % (n as u64 * 64 + 64)).collect();
+            let idl_a = IDLBitRange::from_iter(a.clone());
+            let idl_b = IDLBitRange::from_iter(b.clone());
+
+            let mut idl_scalar = IDLBitRange::new();
+            let mut idl_wide = IDLBitRange::new();
+            let run = idl_a.list.len().min(idl_b.list.len());
+            super::simd::and_ranges_scalar(&idl_a.list[..run], &idl_b.list[..run], &mut idl_scalar);
+            super::simd::and_ranges(&idl_a.list[..run], &idl_b.list[..run], &mut idl_wide);
+            assert_eq!(idl_scalar, idl_wide);
+            assert_eq!(idl_scalar.len(), idl_wide.len());
+
+            let idl_expect = IDLBitRange::from_iter(&(IDLSimple::from_iter(a) & IDLSimple::from_iter(b)));
+            let idl_result = idl_a & idl_b;
+            assert_eq!(idl_result, idl_expect);
+            assert_eq!(idl_result.len(), idl_expect.len());
+        }
+    }
+
+    #[test]
+    fn test_range_intersection_gallop() {
+        let mut next = xorshift(0x9e37_79b9_7f4a_7c15);
+
+        // One bit in each of 100k ranges, against a handful of ranges.
+        let large: Vec<u64> = (0..100000).map(|i| i * 64 + (i % 64)).collect();
+        let idl_large = IDLBitRange::from_iter(large.clone());
+        for _ in 0..64 {
+            let n = 2 + (next() % 16) as usize;
+            let small: Vec<u64> = (0..n).map(|_| next() % (100000 * 64 + 256)).collect();
+            let idl_small = IDLBitRange::from_iter(small.clone());
+
+            let idl_expect = IDLBitRange::from_iter(
+                &(IDLSimple::from_iter(small) & IDLSimple::from_iter(large.clone()))
+            );
+            assert_eq!(idl_small.intersection(&idl_large), idl_expect);
+            assert_eq!(idl_large.intersection(&idl_small), idl_expect);
+        }
+
+        // The ends of the large list, and past them.
+        let idl_small = IDLBitRange::from_iter(vec![0, 99999 * 64 + 31, 100000 * 64]);
+        let idl_expect = IDLBitRange::from_iter(vec![0, 99999 * 64 + 31]);
+        assert_eq!(idl_small.intersection(&idl_large), idl_expect);
+        assert_eq!(idl_large.intersection(&idl_small), idl_expect);
+    }
+
+    #[test]
+    fn test_range_intersects() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 70, 128, 5000]);
+
+        // Overlapping, at the start, the end, and a single shared id.
+        assert!(idl_a.intersects(&IDLBitRange::from_iter(vec![1])));
+        assert!(idl_a.intersects(&IDLBitRange::from_iter(vec![5000, 6000])));
+        assert!(idl_a.intersects(&IDLBitRange::from_iter(vec![0, 65, 70, 4000])));
+        assert!(idl_a.intersects(&idl_a));
+
+        // Disjoint, in shared ranges, in gaps and beyond either end.
+        assert!(!idl_a.intersects(&IDLBitRange::from_iter(vec![0, 4, 65, 129, 5001])));
+        assert!(!idl_a.intersects(&IDLBitRange::from_iter(vec![200, 300, 4000])));
+        assert!(!idl_a.intersects(&IDLBitRange::from_iter(vec![9000])));
+        assert!(!idl_a.intersects(&IDLBitRange::new()));
+        assert!(!IDLBitRange::new().intersects(&idl_a));
+
+        // Interleaved sets that share every range but no id.
+        let idl_even = IDLBitRange::from_iter((0..10000).map(|i| i * 2));
+        let idl_odd = IDLBitRange::from_iter((0..10000).map(|i| i * 2 + 1));
+        assert!(!idl_even.intersects(&idl_odd));
+        assert!(idl_even.intersects(&(&idl_odd | &IDLBitRange::from_u64(19998))));
+
+        // Agrees with building the intersection.
+        for idl_b in &[idl_even, idl_odd] {
+            assert_eq!(idl_a.intersects(idl_b), !(&idl_a & idl_b).is_empty());
+            assert_eq!(idl_b.intersects(&idl_a), !(idl_b & &idl_a).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_range_intersection_disjoint() {
+        let idl_low = IDLBitRange::from_iter(0..6400);
+        let idl_high = IDLBitRange::from_iter(6400..12800);
+        let idl_empty = IDLBitRange::new();
+        assert_eq!(idl_low.intersection(&idl_high), idl_empty);
+        assert_eq!(idl_high.intersection(&idl_low), idl_empty);
+        assert_eq!(idl_low.intersection(&idl_empty), idl_empty);
+        assert_eq!(idl_empty.intersection(&idl_low), idl_empty);
+
+        // Only the edge ranges touch.
+        let idl_a = IDLBitRange::from_iter(0..6401);
+        let idl_expect = IDLBitRange::from_iter(vec![6400]);
+        assert_eq!(idl_a.intersection(&idl_high), idl_expect);
+        assert_eq!(idl_high.intersection(&idl_a), idl_expect);
+    }
+
+    #[test]
+    fn test_simple_intersection_6() {
+        let idl_a = IDLSimple::from_iter(vec![307199]);
+        let idl_b = IDLSimple::from_iter(102400..307200);
+        let idl_expect = IDLSimple::from_iter(vec![307199]);
+
+        let idl_result = idl_a & idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_intersection_6() {
+        let idl_a = IDLBitRange::from_iter(vec![307199]);
+        let idl_b = IDLBitRange::from_iter(102400..307200);
+        let idl_expect = IDLBitRange::from_iter(vec![307199]);
+
+        let idl_result = idl_a & idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_simple_union_1() {
+        let idl_a = IDLSimple::from_iter(vec![1,2,3]);
+        let idl_b = IDLSimple::from_iter(vec![2]);
+        let idl_expect = IDLSimple::from_iter(vec![1,2,3]);
+
+        let idl_result = idl_a | idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_union_1() {
+        let idl_a = IDLBitRange::from_iter(vec![1,2,3]);
+        let idl_b = IDLBitRange::from_iter(vec![2]);
+        let idl_expect = IDLBitRange::from_iter(vec![1, 2, 3]);
+
+        let idl_result = idl_a | idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_simple_union_2() {
+        let idl_a = IDLSimple::from_iter(vec![1,2,3]);
+        let idl_b = IDLSimple::from_iter(vec![4,67]);
+        let idl_expect = IDLSimple::from_iter(vec![1,2,3,4,67]);
+
+        let idl_result = idl_a | idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_union_2() {
+        let idl_a = IDLBitRange::from_iter(vec![1,2,3]);
+        let idl_b = IDLBitRange::from_iter(vec![4,67]);
+        let idl_expect = IDLBitRange::from_iter(vec![1,2,3,4,67]);
+
+        let idl_result = idl_a | idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_simple_union_3() {
+        let idl_a = IDLSimple::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
+        let idl_b = IDLSimple::from_iter(1..1024);
+        let idl_expect = IDLSimple::from_iter(1..1024);
+
+        let idl_result = idl_a | idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_union_3() {
+        let idl_a = IDLBitRange::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
+        let idl_b = IDLBitRange::from_iter(1..1024);
+        let idl_expect = IDLBitRange::from_iter(1..1024);
+
+        let idl_result = idl_a | idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_simple_not_1() {
+        let idl_a = IDLSimple::from_iter(vec![1,2,3,4,5,6]);
+        let idl_b = IDLSimple::from_iter(vec![3,4]);
+        let idl_expect = IDLSimple::from_iter(vec![1,2,5,6]);
+
+        let idl_result = idl_a.andnot(idl_b);
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_simple_not_2() {
+        let idl_a = IDLSimple::from_iter(vec![1,2,3,4,5,6]);
+        let idl_b = IDLSimple::from_iter(vec![10]);
+        let idl_expect = IDLSimple::from_iter(vec![1,2,3,4,5,6]);
+
+        let idl_result = idl_a.andnot(idl_b);
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_simple_not_3() {
+        let idl_a = IDLSimple::from_iter(vec![2,3,4,5,6]);
+        let idl_b = IDLSimple::from_iter(vec![1]);
+        let idl_expect = IDLSimple::from_iter(vec![2,3,4,5,6]);
+
+        let idl_result = idl_a.andnot(idl_b);
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_not_1() {
+        let idl_a = IDLBitRange::from_iter(vec![1,2,3,4,5,6]);
+        let idl_b = IDLBitRange::from_iter(vec![3,4]);
+        let idl_expect = IDLBitRange::from_iter(vec![1,2,5,6]);
+
+        let idl_result = idl_a.andnot(idl_b);
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_not_2() {
+        let idl_a = IDLBitRange::from_iter(vec![1,2,3,4,5,6]);
+        let idl_b = IDLBitRange::from_iter(vec![10]);
+        let idl_expect = IDLBitRange::from_iter(vec![1,2,3,4,5,6]);
+
+        let idl_result = idl_a.andnot(idl_b);
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_not_3() {
+        let idl_a = IDLBitRange::from_iter(vec![2,3,4,5,6]);
+        let idl_b = IDLBitRange::from_iter(vec![1]);
+        let idl_expect = IDLBitRange::from_iter(vec![2,3,4,5,6]);
+
+        let idl_result = idl_a.andnot(idl_b);
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_not_4() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 65, 128]);
+        let idl_b = IDLBitRange::from_iter(vec![65, 200]);
+        let idl_expect = IDLBitRange::from_iter(vec![1, 2, 3, 64, 128]);
+
+        let idl_result = idl_a.andnot(idl_b);
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_simple_xor_1() {
+        let idl_a = IDLSimple::from_iter(vec![1,2,3,4,5,6]);
+        let idl_b = IDLSimple::from_iter(vec![3,4,10]);
+        let idl_expect = IDLSimple::from_iter(vec![1,2,5,6,10]);
+
+        let idl_result = idl_a ^ idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_simple_xor_2() {
+        let idl_a = IDLSimple::from_iter(vec![1,2,3]);
+        let idl_b = IDLSimple::from_iter(vec![1,2,3]);
+        let idl_expect = IDLSimple::new();
+
+        let idl_result = idl_a ^ idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_xor_1() {
+        let idl_a = IDLBitRange::from_iter(vec![1,2,3,4,5,6]);
+        let idl_b = IDLBitRange::from_iter(vec![3,4,10]);
+        let idl_expect = IDLBitRange::from_iter(vec![1,2,5,6,10]);
+
+        let idl_result = idl_a ^ idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_xor_2() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 64, 65, 300]);
+        let idl_b = IDLBitRange::from_iter(vec![0, 64, 65, 128, 300]);
+        let idl_expect = IDLBitRange::from_iter(vec![0, 1, 2, 128]);
+
+        let idl_result = idl_a ^ idl_b;
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_range_xor_3() {
+        // Both share the range key 64, but the mask cancels out.
+        let idl_a = IDLBitRange::from_iter(vec![1, 64, 65]);
+        let idl_b = IDLBitRange::from_iter(vec![64, 65]);
+        let idl_expect = IDLBitRange::from_iter(vec![1]);
+
+        let idl_result = idl_a ^ idl_b;
+        assert_eq!(idl_result.len(), 1);
+        assert_eq!(idl_result, idl_expect);
+    }
+
+    #[test]
+    fn test_simple_ref_ops() {
+        let idl_a = IDLSimple::from_iter(vec![1, 2, 3, 4]);
+        let idl_b = IDLSimple::from_iter(vec![3, 4, 5]);
+
+        assert_eq!(idl_a.intersection(&idl_b), IDLSimple::from_iter(vec![3, 4]));
+        // Both operands are still usable.
+        assert_eq!(idl_a.union(&idl_b), IDLSimple::from_iter(vec![1, 2, 3, 4, 5]));
+        assert_eq!(idl_a.difference(&idl_b), IDLSimple::from_iter(vec![1, 2]));
+        assert_eq!(idl_a.intersection(&idl_b), idl_a & idl_b);
+    }
+
+    #[test]
+    fn test_range_ref_ops() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 128]);
+        let idl_b = IDLBitRange::from_iter(vec![3, 64, 200]);
+
+        assert_eq!(idl_a.intersection(&idl_b), IDLBitRange::from_iter(vec![3, 64]));
+        // Both operands are still usable.
+        assert_eq!(idl_a.union(&idl_b), IDLBitRange::from_iter(vec![1, 2, 3, 64, 128, 200]));
+        assert_eq!(idl_a.difference(&idl_b), IDLBitRange::from_iter(vec![1, 2, 128]));
+        assert_eq!(idl_a.intersection(&idl_b), idl_a & idl_b);
+    }
+
+    #[test]
+    fn test_simple_contains() {
+        let idl_a = IDLSimple::new();
+        assert!(!idl_a.contains(0));
+
+        let idl_a = IDLSimple::from_iter(vec![1, 2, 3, 64, 128]);
+        assert!(idl_a.contains(1));
+        assert!(idl_a.contains(128));
+        assert!(!idl_a.contains(0));
+        assert!(!idl_a.contains(65));
+        assert!(!idl_a.contains(4096));
+    }
+
+    #[test]
+    fn test_range_contains() {
+        let idl_a = IDLBitRange::new();
+        assert!(!idl_a.contains(0));
+
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 128]);
+        assert!(idl_a.contains(1));
+        assert!(idl_a.contains(64));
+        assert!(idl_a.contains(128));
+        // In an existing range, but the bit is unset.
+        assert!(!idl_a.contains(0));
+        assert!(!idl_a.contains(65));
+        // Beyond the last range.
+        assert!(!idl_a.contains(192));
+        assert!(!idl_a.contains(u64::MAX));
+    }
+
+    #[test]
+    fn test_simple_remove() {
+        let mut idl_a = IDLSimple::from_iter(vec![1, 2, 3]);
+        idl_a.remove_id(2);
+        assert_eq!(idl_a, IDLSimple::from_iter(vec![1, 3]));
+        idl_a.remove_id(10);
+        assert_eq!(idl_a, IDLSimple::from_iter(vec![1, 3]));
+    }
+
+    #[test]
+    fn test_range_remove() {
+        let mut idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 128]);
+        // Remove a bit from a multi-bit range.
+        idl_a.remove_id(2);
+        assert_eq!(idl_a, IDLBitRange::from_iter(vec![1, 3, 64, 128]));
+        assert_eq!(idl_a.len(), 4);
+        // Remove the last bit of a range, the range is dropped.
+        idl_a.remove_id(64);
+        assert_eq!(idl_a, IDLBitRange::from_iter(vec![1, 3, 128]));
+        assert_eq!(idl_a.list.len(), 2);
+        assert_eq!(idl_a.len(), 3);
+        // Remove absent ids, both in and out of a present range.
+        idl_a.remove_id(4);
+        idl_a.remove_id(1024);
+        assert_eq!(idl_a, IDLBitRange::from_iter(vec![1, 3, 128]));
+        assert_eq!(idl_a.len(), 3);
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let idl_a = IDLSimple::from_iter(vec![1, 2, 3]) & IDLSimple::from_iter(vec![4, 67]);
+        assert!(idl_a.is_empty());
+        let idl_a = IDLSimple::from_iter(vec![1, 2, 3]) & IDLSimple::from_iter(vec![2, 67]);
+        assert!(!idl_a.is_empty());
+
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3]) & IDLBitRange::from_iter(vec![4, 67]);
+        assert!(idl_a.is_empty());
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64]).andnot(IDLBitRange::from_iter(vec![1, 2, 3, 64]));
+        assert!(idl_a.is_empty());
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3]) & IDLBitRange::from_iter(vec![2, 67]);
+        assert!(!idl_a.is_empty());
+    }
+
+    #[test]
+    fn test_simple_sort_dedup() {
+        let mut idl_a = IDLSimple(vec![9, 3, 1, 3, 7, 1, 2]);
+        idl_a.sort();
+        idl_a.dedup();
+        assert_eq!(idl_a, IDLSimple::from_iter(vec![1, 2, 3, 7, 9]));
+        // Already normal sets are left alone.
+        idl_a.sort();
+        idl_a.dedup();
+        assert_eq!(idl_a, IDLSimple::from_iter(vec![1, 2, 3, 7, 9]));
+
+        let idl_b = IDLSimple::from_iter(vec![2, 3, 4, 9]);
+        assert_eq!(&idl_a & &idl_b, IDLSimple::from_iter(vec![2, 3, 9]));
+        assert_eq!(&idl_a | &idl_b, IDLSimple::from_iter(vec![1, 2, 3, 4, 7, 9]));
+        assert_eq!((&idl_a).andnot(&idl_b), IDLSimple::from_iter(vec![1, 7]));
+        assert!(idl_a.contains(7));
+        assert!(!idl_a.contains(8));
+
+        let mut idl_c = IDLSimple::new();
+        idl_c.sort();
+        idl_c.dedup();
+        assert!(idl_c.is_empty());
+    }
+
+    #[test]
+    fn test_push_ids() {
+        let sorted: Vec<u64> = vec![0, 1, 5, 63, 64, 200, 201, 1000, 4096];
+        let unsorted: Vec<u64> = vec![201, 5, 0, 4096, 5, 64, 1000, 1, 63, 200];
+        let base: Vec<u64> = vec![2, 63, 300, 5000];
+        for ids in &[&sorted[..], &unsorted[..], &[][..]] {
+            for start in &[&[][..], &base[..]] {
+                let mut idl_a = IDLBitRange::from_iter(start.iter().cloned());
+                let mut idl_b = idl_a.clone();
+                idl_a.push_ids(ids);
+                for id in ids.iter() {
+                    idl_b.push_id(*id);
+                }
+                assert_eq!(idl_a, idl_b);
+                assert_eq!(idl_a.len(), idl_b.len());
+
+                let mut idl_c = IDLSimple::from_iter(start.iter().cloned());
+                idl_c.push_ids(ids);
+                assert_eq!(idl_c, IDLSimple::from(idl_b));
+            }
+        }
+    }
+
+    #[test]
+    fn test_push_id_bounded() {
+        let mut idl_a = IDLSimple::new();
+        assert_eq!(idl_a.push_id_bounded(5, 10), Ok(()));
+        assert_eq!(idl_a.push_id_bounded(10, 10), Ok(()));
+        assert_eq!(idl_a.push_id_bounded(11, 10), Err(IDLError::OutOfBounds));
+        assert_eq!(idl_a, IDLSimple::from_iter(vec![5, 10]));
+
+        let mut idl_b = IDLBitRange::new();
+        assert_eq!(idl_b.push_id_bounded(0, 1000), Ok(()));
+        assert_eq!(idl_b.push_id_bounded(1000, 1000), Ok(()));
+        assert_eq!(idl_b.push_id_bounded(u64::MAX, 1000), Err(IDLError::OutOfBounds));
+        assert_eq!(idl_b, IDLBitRange::from_iter(vec![0, 1000]));
+        assert_eq!(idl_b.count_ranges(), 2);
+    }
+
+    #[test]
+    fn test_simple_clone() {
+        let idl_a = IDLSimple::from_iter(vec![1, 2, 3]);
+        let mut idl_b = idl_a.clone();
+        assert_eq!(idl_a, idl_b);
+        idl_b.push_id(4);
+        assert_eq!(idl_a, IDLSimple::from_iter(vec![1, 2, 3]));
+        assert_eq!(idl_b, IDLSimple::from_iter(vec![1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn test_range_clone() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64]);
+        let mut idl_b = idl_a.clone();
+        assert_eq!(idl_a, idl_b);
+        idl_b.push_id(65);
+        idl_b.push_id(128);
+        assert_eq!(idl_a, IDLBitRange::from_iter(vec![1, 2, 3, 64]));
+        assert_eq!(idl_a.len(), 4);
+        assert_eq!(idl_b, IDLBitRange::from_iter(vec![1, 2, 3, 64, 65, 128]));
+    }
+
+    #[test]
+    fn test_simple_push_unordered_1() {
+        let mut idl_a = IDLSimple::new();
+        idl_a.push_id(3);
+        idl_a.push_id(1);
+        idl_a.push_id(3);
+        idl_a.push_id(2);
+        let result: Vec<u64> = (&idl_a).into_iter().collect();
+        assert_eq!(result, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_simple_push_unordered_2() {
+        let idl_a = IDLSimple::from_iter(vec![900, 8, 2, 35, 3, 2, 64]);
+        let idl_b = IDLSimple::from_iter((1..1024).rev());
+        let idl_expect = IDLSimple::from_iter(vec![2, 3, 8, 35, 64, 900]);
+
+        assert_eq!(idl_a.intersection(&idl_b), idl_expect);
+        let idl_expect = IDLSimple::from_iter(1..1024);
+        assert_eq!(idl_a | idl_b, idl_expect);
+    }
+
+    #[test]
+    fn test_range_push_unordered_1() {
+        let mut idl_a = IDLBitRange::new();
+        for i in (0..300).rev() {
+            idl_a.push_id(i);
+        }
+        let result: Vec<u64> = (&idl_a).into_iter().collect();
+        let expect: Vec<u64> = (0..300).collect();
+        assert_eq!(result, expect);
+        assert_eq!(idl_a, IDLBitRange::from_iter(0..300));
+    }
+
+    #[test]
+    fn test_range_push_unordered_2() {
+        let idl_a = IDLBitRange::from_iter(vec![200, 5, 640, 70, 5, 199, 64, 1]);
+        let result: Vec<u64> = (&idl_a).into_iter().collect();
+        assert_eq!(result, vec![1, 5, 64, 70, 199, 200, 640]);
+        assert_eq!(idl_a.len(), 7);
+        // Set operations still work with the unordered inputs.
+        let idl_b = IDLBitRange::from_iter(vec![640, 1, 199]);
+        let idl_expect = IDLBitRange::from_iter(vec![1, 199, 640]);
+        assert_eq!(idl_a & idl_b, idl_expect);
+    }
+
+    #[test]
+    fn test_range_len_1() {
+        let mut idl_a = IDLBitRange::new();
+        assert_eq!(idl_a.len(), 0);
+        idl_a.push_id(1);
+        idl_a.push_id(2);
+        idl_a.push_id(2);
+        idl_a.push_id(64);
+        assert_eq!(idl_a.len(), 3);
+        assert_eq!(idl_a.len(), (&idl_a).into_iter().count());
+    }
+
+    #[test]
+    fn test_range_len_2() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 4, 35, 64, 65, 128, 150]);
+        let idl_b = IDLBitRange::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180]);
+        assert_eq!(idl_a.len(), 9);
+        assert_eq!(idl_b.len(), 10);
+
+        let idl_result = IDLBitRange::from_iter(vec![1, 2, 3, 4, 35, 64, 65, 128, 150])
+            & IDLBitRange::from_iter(vec![2]);
+        assert_eq!(idl_result.len(), (&idl_result).into_iter().count());
+
+        let idl_result = idl_a & idl_b;
+        assert_eq!(idl_result.len(), 6);
+        assert_eq!(idl_result.len(), (&idl_result).into_iter().count());
+
+        let idl_result = IDLBitRange::from_iter(1..1024) | IDLBitRange::from_iter(vec![2, 3, 2000]);
+        assert_eq!(idl_result.len(), 1024);
+        assert_eq!(idl_result.len(), (&idl_result).into_iter().count());
+
+        let idl_result = IDLBitRange::from_iter(1..1024).andnot(IDLBitRange::from_iter(vec![2, 3, 2000]));
+        assert_eq!(idl_result.len(), 1021);
+        assert_eq!(idl_result.len(), (&idl_result).into_iter().count());
+    }
+
+    #[test]
+    fn test_range_bytes_1() {
+        let idl_a = IDLBitRange::new();
+        let idl_b = IDLBitRange::from_bytes(&idl_a.to_bytes()).unwrap();
+        assert_eq!(idl_a, idl_b);
+
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 128]);
+        let buf = idl_a.to_bytes();
+        assert_eq!(buf.len(), 8 + 3 * 16);
+        let idl_b = IDLBitRange::from_bytes(&buf).unwrap();
+        assert_eq!(idl_a, idl_b);
+        assert_eq!(idl_b.len(), 5);
+
+        let idl_a = IDLBitRange::from_iter(1..204800);
+        let idl_b = IDLBitRange::from_bytes(&idl_a.to_bytes()).unwrap();
+        assert_eq!(idl_a, idl_b);
+    }
+
+    #[test]
+    fn test_range_bytes_2() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 128]);
+        let buf = idl_a.to_bytes();
+        assert_eq!(IDLBitRange::from_bytes(&buf[..4]), Err(IDLError::Truncated));
+        assert_eq!(IDLBitRange::from_bytes(&buf[..buf.len() - 1]), Err(IDLError::Truncated));
+
+        // Trailing bytes after the last range, partial or whole.
+        let mut bad = buf.clone();
+        bad.push(0);
+        assert_eq!(IDLBitRange::from_bytes(&bad), Err(IDLError::Truncated));
+        let mut bad = buf.clone();
+        bad.extend_from_slice(&buf[8..24]);
+        assert_eq!(IDLBitRange::from_bytes(&bad), Err(IDLError::Truncated));
+
+        // Swap the first two ranges.
+        let mut bad = buf.clone();
+        bad[8..24].copy_from_slice(&buf[24..40]);
+        bad[24..40].copy_from_slice(&buf[8..24]);
+        assert_eq!(IDLBitRange::from_bytes(&bad), Err(IDLError::Unsorted));
+
+        // Zero the last mask.
+        let mut bad = buf.clone();
+        let len = bad.len();
+        for b in &mut bad[len - 8..] {
+            *b = 0;
+        }
+        assert_eq!(IDLBitRange::from_bytes(&bad), Err(IDLError::EmptyMask));
+    }
+
+    #[test]
+    fn test_range_iter_rev() {
+        let idl_a = IDLBitRange::from_iter(vec![0, 1, 2, 63, 64, 65, 127, 300, 1000, 1023]);
+        let forward: Vec<u64> = (&idl_a).into_iter().collect();
+        let mut backward: Vec<u64> = (&idl_a).into_iter().rev().collect();
+        assert_eq!(backward.first(), Some(&1023));
+        backward.reverse();
+        assert_eq!(forward, backward);
+
+        let idl_a = IDLBitRange::new();
+        assert_eq!((&idl_a).into_iter().next_back(), None);
+    }
+
+    #[test]
+    fn test_range_iter_double_ended() {
+        // The two cursors must stop when they meet, including within a range.
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 65, 200]);
+        let mut iter = (&idl_a).into_iter();
+        assert_eq!(iter.next(), Some(1));
+        assert_eq!(iter.next_back(), Some(200));
+        assert_eq!(iter.next_back(), Some(65));
+        assert_eq!(iter.next(), Some(2));
+        assert_eq!(iter.next_back(), Some(64));
+        assert_eq!(iter.next_back(), Some(3));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3]);
+        let mut iter = (&idl_a).into_iter();
+        assert_eq!(iter.next_back(), Some(3));
+        assert_eq!(iter.next(), Some(1));
+        assert_eq!(iter.next_back(), Some(2));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_simple_iter_len() {
+        let idl_a = IDLSimple::from_iter(vec![1, 2, 3, 64]);
+        let mut iter = (&idl_a).into_iter();
+        assert_eq!(iter.size_hint(), (4, Some(4)));
+        for expect in (0..4).rev() {
+            assert!(iter.next().is_some());
+            assert_eq!(iter.len(), expect);
+        }
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.len(), 0);
+    }
+
+    #[test]
+    fn test_simple_into_iter_owned() {
+        let idl_a = IDLSimple::from_iter(vec![1, 5, 64, 900, 1000]);
+        let ids: Vec<u64> = (&idl_a).into_iter().collect();
+
+        let mut owned = Vec::new();
+        for id in idl_a.clone() {
+            owned.push(id);
+        }
+        assert_eq!(owned, ids);
+
+        let mut iter = idl_a.into_iter();
+        assert_eq!(iter.len(), 5);
+        assert_eq!(iter.next_back(), Some(1000));
+        assert_eq!(iter.collect::<Vec<u64>>(), vec![1, 5, 64, 900]);
+    }
+
+    #[test]
+    fn test_range_iter_len() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 65, 130, 1000]);
+        let mut iter = (&idl_a).into_iter();
+        assert_eq!(iter.size_hint(), (7, Some(7)));
+        for expect in (0..7).rev() {
+            assert!(iter.next().is_some());
+            assert_eq!(iter.len(), expect);
+        }
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.len(), 0);
+
+        let mut iter = (&idl_a).into_iter();
+        iter.next_back();
+        iter.next();
+        assert_eq!(iter.len(), 5);
+    }
+
+    #[test]
+    fn test_range_union_all_1() {
+        let idl_result = IDLBitRange::union_all(Vec::new());
+        assert_eq!(idl_result, IDLBitRange::new());
+
+        let idl_result = IDLBitRange::union_all(vec![
+            IDLBitRange::from_iter(vec![1, 2, 3]),
+            IDLBitRange::new(),
+            IDLBitRange::from_iter(vec![2, 64, 300]),
+            IDLBitRange::from_iter(vec![3, 4, 65]),
+        ]);
+        let idl_expect = IDLBitRange::from_iter(vec![1, 2, 3, 4, 64, 65, 300]);
+        assert_eq!(idl_result, idl_expect);
+        assert_eq!(idl_result.len(), 7);
+    }
+
+    #[test]
+    fn test_range_union_all_2() {
+        let datasets: Vec<Vec<u64>> = vec![
+            vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900],
+            (1..1024).collect(),
+            (102400..307200).collect(),
+            vec![307199],
+            (1..204800).collect(),
+        ];
+        let idl_expect = datasets.iter()
+            .map(|d| IDLBitRange::from_iter(d.clone()))
+            .fold(IDLBitRange::new(), |acc, x| acc | x);
+        let idl_result = IDLBitRange::union_all(
+            datasets.iter().map(|d| IDLBitRange::from_iter(d.clone()))
+        );
+        assert_eq!(idl_result, idl_expect);
+        assert_eq!(idl_result.len(), idl_expect.len());
+    }
+
+    #[test]
+    fn test_range_intersection_all_1() {
+        let idl_result = IDLBitRange::intersection_all(Vec::new());
+        assert!(idl_result.is_empty());
+
+        let idl_result = IDLBitRange::intersection_all(vec![
+            IDLBitRange::from_iter(1..1024),
+            IDLBitRange::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]),
+            IDLBitRange::from_iter(vec![2, 3, 35, 64, 128, 150, 152, 180, 256, 900, 1024, 1500, 1600]),
+        ]);
+        let idl_expect = IDLBitRange::from_iter(vec![2, 3, 35, 64, 128, 150, 152, 180, 256, 900]);
         assert_eq!(idl_result, idl_expect);
     }
 
     #[test]
-    fn test_range_intersection_2() {
+    fn test_range_intersection_all_2() {
+        // One empty term means the whole result is empty.
+        let idl_result = IDLBitRange::intersection_all(vec![
+            IDLBitRange::from_iter(1..204800),
+            IDLBitRange::from_iter(102400..307200),
+            IDLBitRange::new(),
+            IDLBitRange::from_iter(1..1024),
+        ]);
+        assert!(idl_result.is_empty());
+        assert_eq!(idl_result, IDLBitRange::new());
+
+        // Count the terms taken, to show none after the empty one are.
+        let visited = ::std::cell::Cell::new(0);
+        let idl_result = IDLBitRange::intersection_all(
+            vec![
+                IDLBitRange::from_iter(1..204800),
+                IDLBitRange::new(),
+                IDLBitRange::from_iter(1..1024),
+                IDLBitRange::from_iter(5..10),
+            ]
+            .into_iter()
+            .inspect(|_| visited.set(visited.get() + 1)),
+        );
+        assert!(idl_result.is_empty());
+        assert_eq!(visited.get(), 2);
+    }
+
+    #[test]
+    fn test_intersection_count() {
+        let idl_a = IDLSimple::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
+        let idl_b = IDLSimple::from_iter(vec![2, 3, 35, 64, 128, 150, 152, 180, 256, 900, 1024, 1500]);
+        assert_eq!(idl_a.intersection_count(&idl_b), 10);
+        assert_eq!(idl_a.intersection_count(&idl_b), (idl_a & idl_b).len());
+
+        let idl_a = IDLBitRange::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
+        let idl_b = IDLBitRange::from_iter(vec![2, 3, 35, 64, 128, 150, 152, 180, 256, 900, 1024, 1500]);
+        assert_eq!(idl_a.intersection_count(&idl_b), 10);
+        assert_eq!(idl_a.intersection_count(&idl_b), (idl_a & idl_b).len());
+
+        let idl_a = IDLBitRange::from_iter(1..204800);
+        let idl_b = IDLBitRange::from_iter(102400..307200);
+        assert_eq!(idl_a.intersection_count(&idl_b), (idl_a & idl_b).len());
+
         let idl_a = IDLBitRange::from_iter(vec![1, 2, 3]);
-        let idl_b = IDLBitRange::from_iter(vec![4, 67]);
-        let idl_expect = IDLBitRange::new();
+        assert_eq!(idl_a.intersection_count(&IDLBitRange::new()), 0);
+    }
 
+    #[test]
+    fn test_range_min_max() {
+        let idl_a = IDLBitRange::new();
+        assert_eq!(idl_a.first(), None);
+        assert_eq!(idl_a.last(), None);
+
+        let idl_a = IDLBitRange::from_iter(vec![70]);
+        assert_eq!(idl_a.first(), Some(70));
+        assert_eq!(idl_a.last(), Some(70));
+
+        let idl_a = IDLBitRange::from_iter(vec![0, 63]);
+        assert_eq!(idl_a.first(), Some(0));
+        assert_eq!(idl_a.last(), Some(63));
+
+        let idl_a = IDLBitRange::from_iter(vec![5, 9, 64, 100, 190, 191]);
+        assert_eq!(idl_a.first(), Some(5));
+        assert_eq!(idl_a.last(), Some(191));
+    }
 
-        let idl_result = idl_a & idl_b;
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_first_last() {
+        let datasets: Vec<Vec<u64>> = vec![
+            Vec::new(),
+            vec![70],
+            vec![0, 63],
+            vec![5, 9, 64, 100, 190, 191],
+            (1..1024).collect(),
+            (0..100).map(|i| i * 1000 + 7).collect(),
+        ];
+        for data in datasets {
+            let idl_a = IDLSimple::from_iter(data.clone());
+            assert_eq!(idl_a.first(), (&idl_a).into_iter().next());
+            assert_eq!(idl_a.last(), (&idl_a).into_iter().last());
+
+            let idl_b = IDLBitRange::from_iter(data);
+            assert_eq!(idl_b.first(), (&idl_b).into_iter().next());
+            assert_eq!(idl_b.last(), (&idl_b).into_iter().next_back());
+            assert_eq!(idl_b.first(), idl_a.first());
+            assert_eq!(idl_b.last(), idl_a.last());
+        }
     }
 
     #[test]
-    fn test_range_intersection_3() {
-        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 4, 35, 64, 65, 128, 150]);
-        let idl_b = IDLBitRange::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180]);
-        let idl_expect = IDLBitRange::from_iter(vec![2, 3, 35, 64, 128, 150]);
+    fn test_range_iter_nth() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 70, 128, 300, 301, 1000]);
+        let ids: Vec<u64> = (&idl_a).into_iter().collect();
+        for k in 0..ids.len() + 2 {
+            assert_eq!((&idl_a).into_iter().nth(k), ids.get(k).cloned());
+        }
 
-        let idl_result = idl_a & idl_b;
-        assert_eq!(idl_result, idl_expect);
+        let idl_a = IDLBitRange::from_iter((0..5000).filter(|i| i % 3 == 0));
+        let ids: Vec<u64> = (&idl_a).into_iter().collect();
+        for k in vec![0, 21, 22, 63, 500, 1665, 1666, 1667, 5000] {
+            assert_eq!((&idl_a).into_iter().nth(k), ids.get(k).cloned());
+        }
+
+        // Repeated skips continue from where the last left off, and respect
+        // ids already taken from the back.
+        let mut iter = (&idl_a).into_iter();
+        assert_eq!(iter.next_back(), ids.last().cloned());
+        assert_eq!(iter.nth(30), Some(ids[30]));
+        assert_eq!(iter.nth(100), Some(ids[131]));
+        assert_eq!(iter.len(), ids.len() - 133);
+        assert_eq!(iter.nth(ids.len() - 134), Some(ids[ids.len() - 2]));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.len(), 0);
+
+        let mut iter = (&idl_a).into_iter();
+        iter.next_back();
+        assert_eq!(iter.nth(ids.len() - 1), None);
     }
 
     #[test]
-    fn test_simple_intersection_4() {
-        let idl_a = IDLSimple::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
-        let idl_b = IDLSimple::from_iter(1..1024);
-        let idl_expect = IDLSimple::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
+    fn test_range_into_iter_owned() {
+        let idl_a = IDLBitRange::from_iter((0..5000).filter(|i| i % 3 == 0));
+        let ids: Vec<u64> = (&idl_a).into_iter().collect();
 
-        let idl_result = idl_a & idl_b;
-        assert_eq!(idl_result, idl_expect);
+        let mut owned = Vec::new();
+        for id in idl_a.clone() {
+            owned.push(id);
+        }
+        assert_eq!(owned, ids);
+
+        // The owned iterator can move to another thread.
+        let idl_b = idl_a.clone();
+        let sum = ::std::thread::spawn(move || idl_b.into_iter().sum::<u64>()).join().unwrap();
+        assert_eq!(sum, ids.iter().sum::<u64>());
+
+        let mut iter = idl_a.into_iter();
+        assert_eq!(iter.len(), ids.len());
+        assert_eq!(iter.next_back(), ids.last().cloned());
+        assert_eq!(iter.nth(30), Some(ids[30]));
+        assert_eq!(iter.next(), Some(ids[31]));
+        assert_eq!(iter.len(), ids.len() - 33);
+        assert_eq!(iter.nth(ids.len()), None);
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
     }
 
     #[test]
-    fn test_range_intersection_4() {
-        let idl_a = IDLBitRange::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
-        let idl_b = IDLBitRange::from_iter(1..1024);
-        let idl_expect = IDLBitRange::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
+    fn test_range_rank() {
+        let idl_a = IDLBitRange::new();
+        assert_eq!(idl_a.rank(0), 0);
+        assert_eq!(idl_a.rank(100), 0);
+
+        let idl_a = IDLBitRange::from_iter(vec![0, 1, 5, 63, 64, 65, 200, 1000]);
+        assert_eq!(idl_a.rank(0), 0);
+        assert_eq!(idl_a.rank(1), 1);
+        assert_eq!(idl_a.rank(5), 2);
+        assert_eq!(idl_a.rank(6), 3);
+        assert_eq!(idl_a.rank(64), 4);
+        assert_eq!(idl_a.rank(128), 6);
+        assert_eq!(idl_a.rank(1000), 7);
+        assert_eq!(idl_a.rank(idl_a.last().unwrap() + 1), idl_a.len());
+
+        let mut last = 0;
+        for id in 0..1100 {
+            let rank = idl_a.rank(id);
+            assert!(rank >= last);
+            last = rank;
+        }
+    }
 
-        let idl_result = idl_a & idl_b;
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_range_select() {
+        let idl_a = IDLBitRange::new();
+        assert_eq!(idl_a.select(0), None);
+
+        let idl_a = IDLBitRange::from_iter(vec![0, 1, 5, 63, 64, 65, 200, 1000]);
+        assert_eq!(idl_a.select(0), Some(0));
+        assert_eq!(idl_a.select(3), Some(63));
+        assert_eq!(idl_a.select(4), Some(64));
+        assert_eq!(idl_a.select(6), Some(200));
+        assert_eq!(idl_a.select(7), Some(1000));
+        assert_eq!(idl_a.select(8), None);
+
+        for id in &idl_a {
+            assert_eq!(idl_a.select(idl_a.rank(id)), Some(id));
+        }
     }
 
     #[test]
-    fn test_simple_intersection_5() {
-        let idl_a = IDLSimple::from_iter(1..204800);
-        let idl_b = IDLSimple::from_iter(102400..307200);
-        let idl_expect = IDLSimple::from_iter(102400..204800);
+    fn test_range_ranges() {
+        let idl_a = IDLBitRange::from_iter(vec![300, 1, 2, 3, 64, 128, 129]);
+        let ranges: Vec<(u64, u64)> = idl_a.ranges().collect();
+        assert_eq!(ranges, vec![(0, 14), (64, 1), (128, 3), (256, 1 << 44)]);
+
+        let idl_a = IDLBitRange::from_iter(vec![5, 9, 1000, 640, 2000, 1, 70])
+            | IDLBitRange::from_iter(vec![3, 800]);
+        let mut last = None;
+        for (base, mask) in idl_a.ranges() {
+            assert_eq!(base % 64, 0);
+            assert!(mask != 0);
+            assert!(last.map(|l| l < base).unwrap_or(true));
+            last = Some(base);
+        }
+        assert_eq!(idl_a.ranges().count(), 6);
+    }
 
-        let idl_result = idl_a & idl_b;
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_range_range_query() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 63, 64, 100, 128, 1000, 2000, 2001]);
+        // Inclusive on both ends.
+        let idl_result = idl_a.range_query(2, 1000);
+        assert_eq!(idl_result, IDLBitRange::from_iter(vec![2, 3, 63, 64, 100, 128, 1000]));
+        assert_eq!(idl_result.len(), 7);
+        assert_eq!(idl_a.range_query(0, u64::MAX), idl_a);
+        assert_eq!(idl_a.range_query(2000, 2000), IDLBitRange::from_iter(vec![2000]));
+
+        // Window inside a single range.
+        let idl_result = idl_a.range_query(2, 62);
+        assert_eq!(idl_result, IDLBitRange::from_iter(vec![2, 3]));
+
+        // Window spanning no ids.
+        assert!(idl_a.range_query(129, 999).is_empty());
+        assert!(idl_a.range_query(3000, 4000).is_empty());
+        assert!(idl_a.range_query(100, 2).is_empty());
     }
 
     #[test]
-    fn test_range_intersection_5() {
-        let idl_a = IDLBitRange::from_iter(1..204800);
-        let idl_b = IDLBitRange::from_iter(102400..307200);
-        let idl_expect = IDLBitRange::from_iter(102400..204800);
+    fn test_range_contains_all_in() {
+        let idl_a = IDLBitRange::from_iter((10..300).chain(400..420).chain(500..501));
+
+        // Fully covered, within one range and across several.
+        assert!(idl_a.contains_all_in(10, 10));
+        assert!(idl_a.contains_all_in(20, 40));
+        assert!(idl_a.contains_all_in(10, 299));
+        assert!(idl_a.contains_all_in(63, 64));
+        assert!(idl_a.contains_all_in(400, 419));
+        assert!(idl_a.contains_all_in(500, 500));
+
+        // One id missing at either edge, or inside.
+        assert!(!idl_a.contains_all_in(9, 299));
+        assert!(!idl_a.contains_all_in(10, 300));
+        let mut idl_b = idl_a.clone();
+        idl_b.remove_id(150);
+        assert!(!idl_b.contains_all_in(10, 299));
+        assert!(idl_b.contains_all_in(151, 299));
+
+        // The window touches a range that is absent.
+        assert!(!idl_a.contains_all_in(290, 410));
+        assert!(!idl_a.contains_all_in(320, 330));
+        assert!(!idl_a.contains_all_in(1000, 1001));
+        assert!(!idl_a.contains_all_in(500, 501));
+        assert!(!IDLBitRange::new().contains_all_in(0, 0));
+
+        // An empty window, and one at the very top of id space.
+        assert!(idl_a.contains_all_in(5, 4));
+        let idl_c = IDLBitRange::from_iter((u64::MAX - 100)..=u64::MAX);
+        assert!(idl_c.contains_all_in(u64::MAX - 100, u64::MAX));
+        assert!(!idl_c.contains_all_in(u64::MAX - 101, u64::MAX));
+    }
 
-        let idl_result = idl_a & idl_b;
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_range_iter_ranges_overlapping() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 63, 64, 100, 128, 1000, 2000, 2001]);
+        let window = |lo, hi| idl_a.iter_ranges_overlapping(lo, hi).collect::<Vec<_>>();
+
+        // Aligned to range boundaries, so masks are untouched.
+        assert_eq!(window(0, 127), vec![(0, 0b1110 | 1 << 63), (64, 1 | 1 << 36)]);
+        assert_eq!(window(64, 191), vec![(64, 1 | 1 << 36), (128, 1)]);
+        assert_eq!(window(0, u64::MAX), idl_a.ranges().collect::<Vec<_>>());
+
+        // Crossing range boundaries, so the edge masks are trimmed.
+        assert_eq!(window(3, 64), vec![(0, 1 << 3 | 1 << 63), (64, 1)]);
+        assert_eq!(window(2, 2), vec![(0, 1 << 2)]);
+        assert_eq!(window(100, 1000), vec![(64, 1 << 36), (128, 1), (960, 1 << 40)]);
+
+        // Windows whose edge ranges trim to nothing, or hold no ranges.
+        assert_eq!(window(4, 62), vec![]);
+        assert_eq!(window(101, 127), vec![]);
+        assert_eq!(window(3000, 4000), vec![]);
+        assert_eq!(window(100, 2), vec![]);
     }
 
     #[test]
-    fn test_simple_intersection_6() {
-        let idl_a = IDLSimple::from_iter(vec![307199]);
-        let idl_b = IDLSimple::from_iter(102400..307200);
-        let idl_expect = IDLSimple::from_iter(vec![307199]);
+    fn test_range_difference_count() {
+        let datasets: Vec<(Vec<u64>, Vec<u64>)> = vec![
+            (Vec::new(), Vec::new()),
+            (vec![1, 2, 3], Vec::new()),
+            (Vec::new(), vec![1, 2, 3]),
+            (vec![1, 2, 3, 64, 65, 900], vec![2, 3, 4, 65, 1000]),
+            ((0..5000).collect(), (0..5000).filter(|i| i % 3 == 0).collect()),
+            ((0..5000).map(|i| i * 7).collect(), (2500..10000).collect()),
+        ];
+        for (a, b) in datasets {
+            let idl_a = IDLBitRange::from_iter(a);
+            let idl_b = IDLBitRange::from_iter(b);
+            let idl_expect = idl_a.clone().andnot(idl_b.clone());
+            assert_eq!(idl_a.andnot_ref(&idl_b), idl_expect);
+            assert_eq!(idl_a.difference_count(&idl_b), idl_expect.len());
+        }
+    }
 
-        let idl_result = idl_a & idl_b;
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_range_jaccard() {
+        let idl_empty = IDLBitRange::new();
+        assert_eq!(idl_empty.jaccard(&idl_empty), 0.0);
+
+        let idl_a = IDLBitRange::from_iter((0..1000).map(|i| i * 3));
+        assert_eq!(idl_a.jaccard(&idl_a.clone()), 1.0);
+        assert_eq!(idl_a.jaccard(&idl_empty), 0.0);
+        assert_eq!(idl_empty.jaccard(&idl_a), 0.0);
+
+        let idl_b = IDLBitRange::from_iter(5000..6000);
+        assert_eq!(idl_a.jaccard(&idl_b), 0.0);
+
+        // 2 shared of 8 distinct ids, with ranges unique to either side.
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 65, 900]);
+        let idl_b = IDLBitRange::from_iter(vec![3, 4, 65, 1000]);
+        assert_eq!(idl_a.jaccard(&idl_b), 2.0 / 8.0);
+        assert_eq!(idl_b.jaccard(&idl_a), 2.0 / 8.0);
     }
 
     #[test]
-    fn test_range_intersection_6() {
-        let idl_a = IDLBitRange::from_iter(vec![307199]);
-        let idl_b = IDLBitRange::from_iter(102400..307200);
-        let idl_expect = IDLBitRange::from_iter(vec![307199]);
+    fn test_range_concat_disjoint() {
+        let mut idl_a = IDLBitRange::from_iter(vec![1, 2, 64, 70]);
+        assert_eq!(idl_a.concat_disjoint(IDLBitRange::from_iter(vec![128, 300])), Ok(()));
+        assert_eq!(idl_a, IDLBitRange::from_iter(vec![1, 2, 64, 70, 128, 300]));
+        assert_eq!(idl_a.len(), 6);
+
+        // Sharing the range at the seam.
+        assert_eq!(idl_a.concat_disjoint(IDLBitRange::from_iter(vec![301, 1000])), Ok(()));
+        assert_eq!(idl_a, IDLBitRange::from_iter(vec![1, 2, 64, 70, 128, 300, 301, 1000]));
+        assert_eq!(idl_a.len(), 8);
+        assert_eq!(idl_a.count_ranges(), 5);
+
+        // Overlapping or out of order sets are refused.
+        let idl_b = idl_a.clone();
+        assert_eq!(idl_a.concat_disjoint(IDLBitRange::from_iter(vec![1000, 2000])), Err(IDLError::Unsorted));
+        assert_eq!(idl_a.concat_disjoint(IDLBitRange::from_iter(vec![999])), Err(IDLError::Unsorted));
+        assert_eq!(idl_a.concat_disjoint(IDLBitRange::from_iter(vec![0])), Err(IDLError::Unsorted));
+        assert_eq!(idl_a, idl_b);
+        assert_eq!(idl_a.len(), 8);
+
+        // Either side empty.
+        assert_eq!(idl_a.concat_disjoint(IDLBitRange::new()), Ok(()));
+        assert_eq!(idl_a, idl_b);
+        let mut idl_c = IDLBitRange::new();
+        assert_eq!(idl_c.concat_disjoint(idl_b.clone()), Ok(()));
+        assert_eq!(idl_c, idl_b);
+        assert_eq!(idl_c.len(), idl_b.len());
+    }
 
-        let idl_result = idl_a & idl_b;
+    #[test]
+    fn test_range_collect_sets() {
+        let idl_1 = IDLBitRange::from_iter(vec![1, 2, 3, 64]);
+        let idl_2 = IDLBitRange::from_iter(vec![3, 65, 500]);
+        let idl_3 = IDLBitRange::from_iter(vec![0, 1000, 64]);
+        let idl_expect = idl_1.union(&idl_2).union(&idl_3);
+        let idl_result = vec![idl_1, idl_2, idl_3].into_iter().collect::<IDLBitRange>();
         assert_eq!(idl_result, idl_expect);
+        assert_eq!(idl_result.len(), 8);
+
+        let idl_empty: IDLBitRange = Vec::<IDLBitRange>::new().into_iter().collect();
+        assert!(idl_empty.is_empty());
     }
 
     #[test]
-    fn test_simple_union_1() {
-        let idl_a = IDLSimple::from_iter(vec![1,2,3]);
-        let idl_b = IDLSimple::from_iter(vec![2]);
-        let idl_expect = IDLSimple::from_iter(vec![1,2,3]);
+    fn test_range_extend_sets() {
+        let idl_1 = IDLBitRange::from_iter(vec![1, 2, 3, 64]);
+        let idl_2 = IDLBitRange::from_iter(vec![3, 65, 500]);
+        let idl_3 = IDLBitRange::from_iter(vec![0, 1000, 64]);
+        let mut idl_acc = IDLBitRange::from_iter(vec![2, 4000]);
+        let idl_expect = &(&(&idl_acc | &idl_1) | &idl_2) | &idl_3;
+        idl_acc.extend(vec![idl_1, idl_2, idl_3]);
+        assert_eq!(idl_acc, idl_expect);
+        assert_eq!(idl_acc.len(), 9);
+
+        idl_acc.extend(Vec::<IDLBitRange>::new());
+        assert_eq!(idl_acc, idl_expect);
+    }
 
-        let idl_result = idl_a | idl_b;
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_range_union_into() {
+        let datasets: Vec<(Vec<u64>, Vec<u64>)> = vec![
+            (Vec::new(), Vec::new()),
+            (vec![1, 2, 3], Vec::new()),
+            (Vec::new(), vec![1, 2, 3]),
+            (vec![1], (1..102400).collect()),
+            ((1..102400).collect(), vec![1]),
+            (vec![1, 2, 3, 64, 65, 900], vec![0, 3, 4, 65, 200, 1000, 5000]),
+            ((0..5000).map(|i| i * 7).collect(), (2500..10000).collect()),
+            ((0..3000).map(|i| i * 128).collect(), (0..3000).map(|i| i * 128 + 64).collect()),
+        ];
+        for (a, b) in datasets {
+            let idl_a = IDLBitRange::from_iter(a);
+            let idl_b = IDLBitRange::from_iter(b);
+            let idl_expect = idl_a.union(&idl_b);
+
+            let mut idl_result = idl_a.clone();
+            idl_result.union_into(&idl_b);
+            assert_eq!(idl_result, idl_expect);
+            assert_eq!(idl_result.len(), idl_expect.len());
+
+            let mut idl_result = idl_b.clone();
+            idl_result.union_into(&idl_a);
+            assert_eq!(idl_result, idl_expect);
+            assert_eq!(idl_result.len(), idl_expect.len());
+
+            let idl_result = idl_a.clone() | idl_b.clone();
+            assert_eq!(idl_result, idl_expect);
+            assert_eq!(idl_result.len(), idl_expect.len());
+        }
     }
 
     #[test]
-    fn test_range_union_1() {
-        let idl_a = IDLBitRange::from_iter(vec![1,2,3]);
-        let idl_b = IDLBitRange::from_iter(vec![2]);
-        let idl_expect = IDLBitRange::from_iter(vec![1, 2, 3]);
+    fn test_range_ord() {
+        let idl_a = IDLBitRange::from_iter(vec![1]);
+        let idl_b = IDLBitRange::from_iter(vec![1, 2]);
+        let idl_c = IDLBitRange::from_iter(vec![2]);
+        let idl_empty = IDLBitRange::new();
+        assert!(idl_empty < idl_a);
+        assert!(idl_a < idl_b);
+        assert!(idl_b < idl_c);
+        assert_eq!(idl_a.cmp(&idl_a.clone()), ::std::cmp::Ordering::Equal);
+
+        let set: ::std::collections::BTreeSet<IDLBitRange> =
+            vec![idl_c.clone(), idl_a.clone(), idl_empty.clone(), idl_b.clone(), idl_a.clone()]
+                .into_iter()
+                .collect();
+        assert_eq!(set.into_iter().collect::<Vec<_>>(), vec![idl_empty, idl_a, idl_b, idl_c]);
+
+        // Agrees with comparing the id sequences, across range boundaries.
+        let mut next = xorshift(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let mut gen = || {
+                let n = next() % 6;
+                IDLBitRange::from_iter((0..n).map(|_| next() % 160))
+            };
+            let idl_a = gen();
+            let idl_b = gen();
+            let expect = (&idl_a).into_iter().cmp(&idl_b);
+            assert_eq!(idl_a.cmp(&idl_b), expect);
+            assert_eq!(idl_b.cmp(&idl_a), expect.reverse());
+            assert_eq!(idl_a == idl_b, expect == ::std::cmp::Ordering::Equal);
+        }
+    }
 
-        let idl_result = idl_a | idl_b;
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_range_take_ids() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 70, 128, 300, 301]);
+        // At a range boundary.
+        let idl_result = idl_a.take_ids(3);
+        assert_eq!(idl_result, IDLBitRange::from_iter(vec![1, 2, 3]));
+        assert_eq!(idl_result.len(), 3);
+        // Mid range.
+        let idl_result = idl_a.take_ids(7);
+        assert_eq!(idl_result, IDLBitRange::from_iter(vec![1, 2, 3, 64, 70, 128, 300]));
+        assert_eq!(idl_result.len(), 7);
+        let idl_result = idl_a.take_ids(4);
+        assert_eq!(idl_result, IDLBitRange::from_iter(vec![1, 2, 3, 64]));
+
+        assert!(idl_a.take_ids(0).is_empty());
+        assert_eq!(idl_a.take_ids(8), idl_a);
+        assert_eq!(idl_a.take_ids(100), idl_a);
+        assert!(IDLBitRange::new().take_ids(10).is_empty());
+
+        let idl_a = IDLBitRange::from_iter(1..10000);
+        let idl_result = idl_a.take_ids(5000);
+        assert_eq!(idl_result, IDLBitRange::from_iter(1..5001));
+        assert_eq!(idl_result.len(), 5000);
     }
 
     #[test]
-    fn test_simple_union_2() {
-        let idl_a = IDLSimple::from_iter(vec![1,2,3]);
-        let idl_b = IDLSimple::from_iter(vec![4,67]);
-        let idl_expect = IDLSimple::from_iter(vec![1,2,3,4,67]);
+    fn test_range_retain() {
+        let mut idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 65, 67, 128, 130, 301]);
+        let mut seen = Vec::new();
+        idl_a.retain(|id| {
+            seen.push(id);
+            id % 2 == 0
+        });
+        assert_eq!(seen, vec![1, 2, 3, 65, 67, 128, 130, 301]);
+        assert_eq!(idl_a, IDLBitRange::from_iter(vec![2, 128, 130]));
+        assert_eq!(idl_a.len(), 3);
+        // The ranges at 64 and 256 held only odd ids, and are gone.
+        assert_eq!(idl_a.list.len(), 2);
+
+        idl_a.retain(|_| false);
+        assert!(idl_a.is_empty());
+        assert_eq!(idl_a.list.len(), 0);
+    }
 
-        let idl_result = idl_a | idl_b;
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_range_split_at() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 70, 128, 130, 301]);
+
+        // On a range boundary.
+        let (lower, upper) = idl_a.split_at(128);
+        assert_eq!(lower, IDLBitRange::from_iter(vec![1, 2, 3, 64, 70]));
+        assert_eq!(upper, IDLBitRange::from_iter(vec![128, 130, 301]));
+        assert_eq!(lower.len(), 5);
+        assert_eq!(upper.len(), 3);
+        assert_eq!(lower | upper, idl_a);
+
+        // Mid mask, leaving ids on both sides.
+        let (lower, upper) = idl_a.split_at(65);
+        assert_eq!(lower, IDLBitRange::from_iter(vec![1, 2, 3, 64]));
+        assert_eq!(upper, IDLBitRange::from_iter(vec![70, 128, 130, 301]));
+        assert_eq!(lower.len() + upper.len(), idl_a.len());
+
+        // Mid mask, leaving one side of the range empty.
+        let (lower, upper) = idl_a.split_at(4);
+        assert_eq!(lower, IDLBitRange::from_iter(vec![1, 2, 3]));
+        assert_eq!(upper.list.first().unwrap().range, 64);
+        assert_eq!(upper.len(), 5);
+        let (lower, upper) = idl_a.split_at(1);
+        assert!(lower.is_empty());
+        assert_eq!(lower.list.len(), 0);
+        assert_eq!(upper, idl_a);
+
+        let (lower, upper) = idl_a.split_at(1000);
+        assert_eq!(lower, idl_a);
+        assert!(upper.is_empty());
     }
 
     #[test]
-    fn test_range_union_2() {
-        let idl_a = IDLBitRange::from_iter(vec![1,2,3]);
-        let idl_b = IDLBitRange::from_iter(vec![4,67]);
-        let idl_expect = IDLBitRange::from_iter(vec![1,2,3,4,67]);
+    fn test_range_ids_after() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 63, 64, 70, 128, 130, 301]);
+
+        // On the last and first bits of a range.
+        assert_eq!(idl_a.ids_after(63), IDLBitRange::from_iter(vec![64, 70, 128, 130, 301]));
+        assert_eq!(idl_a.ids_after(64), IDLBitRange::from_iter(vec![70, 128, 130, 301]));
+        assert_eq!(idl_a.ids_after(127), IDLBitRange::from_iter(vec![128, 130, 301]));
+
+        // Mid range, with and without ids left in that range.
+        let idl_b = idl_a.ids_after(2);
+        assert_eq!(idl_b, IDLBitRange::from_iter(vec![3, 63, 64, 70, 128, 130, 301]));
+        assert_eq!(idl_b.len(), 7);
+        let idl_b = idl_a.ids_after(71);
+        assert_eq!(idl_b, IDLBitRange::from_iter(vec![128, 130, 301]));
+        assert_eq!(idl_b.count_ranges(), 2);
+
+        // Before and beyond all ids.
+        assert_eq!(idl_a.ids_after(0), idl_a);
+        assert!(idl_a.ids_after(301).is_empty());
+        assert!(idl_a.ids_after(5000).is_empty());
+        assert!(idl_a.ids_after(u64::MAX).is_empty());
+        assert!(IDLBitRange::new().ids_after(0).is_empty());
+
+        // Paging through in steps of 3 visits every id once.
+        let mut cursor = 0;
+        let mut seen = IDLBitRange::new();
+        loop {
+            let page = idl_a.ids_after(cursor).take_ids(3);
+            match page.last() {
+                Some(l) => cursor = l,
+                None => break,
+            }
+            seen |= page;
+        }
+        assert_eq!(seen, idl_a);
+    }
 
-        let idl_result = idl_a | idl_b;
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_range_ids_before() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 63, 64, 70, 128, 130, 301]);
+
+        // At or below the lowest id.
+        assert!(idl_a.ids_before(1).is_empty());
+        assert!(idl_a.ids_before(0).is_empty());
+        assert!(IDLBitRange::new().ids_before(u64::MAX).is_empty());
+
+        // Mid range, with and without ids left in that range.
+        let idl_b = idl_a.ids_before(70);
+        assert_eq!(idl_b, IDLBitRange::from_iter(vec![1, 2, 3, 63, 64]));
+        assert_eq!(idl_b.len(), 5);
+        let idl_b = idl_a.ids_before(128 + 1);
+        assert_eq!(idl_b, IDLBitRange::from_iter(vec![1, 2, 3, 63, 64, 70, 128]));
+        let idl_b = idl_a.ids_before(100);
+        assert_eq!(idl_b, IDLBitRange::from_iter(vec![1, 2, 3, 63, 64, 70]));
+        assert_eq!(idl_b.count_ranges(), 2);
+
+        // On the first bit of a range, which drops that range.
+        assert_eq!(idl_a.ids_before(64), IDLBitRange::from_iter(vec![1, 2, 3, 63]));
+        assert_eq!(idl_a.ids_before(128).count_ranges(), 2);
+
+        // Above all ids.
+        assert_eq!(idl_a.ids_before(302), idl_a);
+        assert_eq!(idl_a.ids_before(u64::MAX), idl_a);
+        assert_eq!(idl_a.ids_before(301) | idl_a.ids_after(300), idl_a);
     }
 
     #[test]
-    fn test_simple_union_3() {
-        let idl_a = IDLSimple::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
-        let idl_b = IDLSimple::from_iter(1..1024);
-        let idl_expect = IDLSimple::from_iter(1..1024);
+    fn test_range_from_ranges() {
+        let idl_a = IDLBitRange::from_iter(vec![300, 1, 2, 3, 64, 128, 129]);
+        let idl_b = IDLBitRange::from_ranges(idl_a.ranges()).unwrap();
+        assert_eq!(idl_a, idl_b);
+        assert_eq!(idl_b.len(), 7);
+
+        let idl_b = IDLBitRange::from_ranges(vec![(0, 14), (128, 3)]).unwrap();
+        assert_eq!(idl_b, IDLBitRange::from_iter(vec![1, 2, 3, 128, 129]));
+        assert_eq!(IDLBitRange::from_ranges(Vec::new()), Ok(IDLBitRange::new()));
+    }
 
-        let idl_result = idl_a | idl_b;
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_range_from_ranges_invalid() {
+        assert_eq!(IDLBitRange::from_ranges(vec![(0, 14), (65, 1)]), Err(IDLError::Unaligned));
+        assert_eq!(IDLBitRange::from_ranges(vec![(128, 14), (64, 1)]), Err(IDLError::Unsorted));
+        assert_eq!(IDLBitRange::from_ranges(vec![(64, 14), (64, 1)]), Err(IDLError::Unsorted));
+        assert_eq!(IDLBitRange::from_ranges(vec![(0, 14), (64, 0)]), Err(IDLError::EmptyMask));
     }
 
     #[test]
-    fn test_range_union_3() {
-        let idl_a = IDLBitRange::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900]);
+    fn test_range_reserve() {
+        let mut idl_a = IDLBitRange::new();
+        idl_a.reserve(100);
+        assert!(idl_a.list.capacity() >= 100);
+        idl_a.extend(vec![1, 2, 64]);
+        idl_a.shrink_to_fit();
+        assert_eq!(idl_a.list.capacity(), 2);
+        assert_eq!(idl_a, IDLBitRange::from_iter(vec![1, 2, 64]));
+
         let idl_b = IDLBitRange::from_iter(1..1024);
-        let idl_expect = IDLBitRange::from_iter(1..1024);
+        let idl_result = idl_a.union(&idl_b);
+        assert!(idl_result.list.capacity() >= 16);
+        assert_eq!(idl_result, idl_b);
+    }
 
-        let idl_result = idl_a | idl_b;
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_range_push_range() {
+        let mut idl_a = IDLBitRange::new();
+        let mut idl_b = IDLBitRange::new();
+        let masks: Vec<(u64, u64)> = vec![
+            (640, 0b1011),
+            (0, u64::MAX),
+            (128, 1 << 63),
+            // Overlaps what is already in the 640 range.
+            (640, 0b1110_0000_0001),
+            (128, 0),
+        ];
+        for &(base, mask) in &masks {
+            assert_eq!(idl_a.push_range(base, mask), Ok(()));
+            for bit in 0..64 {
+                if mask & (1 << bit) != 0 {
+                    idl_b.push_id(base + bit);
+                }
+            }
+        }
+        assert_eq!(idl_a, idl_b);
+        assert_eq!(idl_a.len(), idl_b.len());
+        assert_eq!(idl_a.len(), 64 + 1 + 6);
+
+        assert_eq!(idl_a.push_range(65, 1), Err(IDLError::Unaligned));
+        assert_eq!(idl_a, idl_b);
     }
 
     #[test]
-    fn test_simple_not_1() {
-        let idl_a = IDLSimple::from_iter(vec![1,2,3,4,5,6]);
-        let idl_b = IDLSimple::from_iter(vec![3,4]);
-        let idl_expect = IDLSimple::from_iter(vec![1,2,5,6]);
+    fn test_range_from_sorted_slice() {
+        let datasets: Vec<Vec<u64>> = vec![
+            Vec::new(),
+            vec![0],
+            vec![63, 64],
+            vec![1, 2, 3, 64, 70, 128, 300, 301, 1000, u64::MAX],
+            (1..204800).collect(),
+            (0..10000).map(|i| i * 67).collect(),
+        ];
+        for ids in datasets {
+            let idl_a = IDLBitRange::from_sorted_slice(&ids);
+            let idl_b = IDLBitRange::from_iter(ids.clone());
+            assert_eq!(idl_a, idl_b);
+            assert_eq!(idl_a.len(), ids.len());
+            assert_eq!((&idl_a).into_iter().collect::<Vec<u64>>(), ids);
+        }
+    }
 
-        let idl_result = idl_a.andnot(idl_b);
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    #[should_panic]
+    #[cfg(debug_assertions)]
+    fn test_range_from_sorted_slice_unsorted() {
+        IDLBitRange::from_sorted_slice(&[5, 3]);
     }
 
     #[test]
-    fn test_simple_not_2() {
-        let idl_a = IDLSimple::from_iter(vec![1,2,3,4,5,6]);
-        let idl_b = IDLSimple::from_iter(vec![10]);
-        let idl_expect = IDLSimple::from_iter(vec![1,2,3,4,5,6]);
+    fn test_range_optimize() {
+        let mut idl_a = IDLBitRange {
+            list: vec![
+                IDLRange::new(0, 0),
+                IDLRange::new(64, 0b101),
+                IDLRange::new(128, 0),
+                IDLRange::new(192, 1),
+                IDLRange::new(256, 0),
+            ],
+            count: 3,
+        };
+        idl_a.optimize();
+        assert_eq!(idl_a, IDLBitRange::from_iter(vec![64, 66, 192]));
+        assert_eq!(idl_a.len(), 3);
+        assert_eq!(idl_a.count_ranges(), 2);
+        assert_eq!(idl_a.first(), Some(64));
+
+        // Already clean sets are unchanged.
+        let idl_b = IDLBitRange::from_iter(vec![1, 2, 3, 640]);
+        let mut idl_c = idl_b.clone();
+        idl_c.optimize();
+        assert_eq!(idl_b, idl_c);
+
+        let mut idl_d = IDLBitRange::new();
+        idl_d.optimize();
+        assert!(idl_d.is_empty());
+    }
 
-        let idl_result = idl_a.andnot(idl_b);
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_range_clear() {
+        let mut idl_a = IDLBitRange::from_iter((0..1000).map(|i| i * 3));
+        let capacity = idl_a.list.capacity();
+        idl_a.clear();
+        assert!(idl_a.is_empty());
+        assert_eq!(idl_a.len(), 0);
+        assert_eq!(idl_a, IDLBitRange::new());
+        assert_eq!(idl_a.list.capacity(), capacity);
+
+        idl_a.push_id(5);
+        idl_a.push_id(500);
+        assert_eq!(idl_a.list.capacity(), capacity);
+        assert_eq!(idl_a, IDLBitRange::from_iter(vec![5, 500]));
     }
 
     #[test]
-    fn test_simple_not_3() {
-        let idl_a = IDLSimple::from_iter(vec![2,3,4,5,6]);
-        let idl_b = IDLSimple::from_iter(vec![1]);
-        let idl_expect = IDLSimple::from_iter(vec![2,3,4,5,6]);
+    fn test_ref_operators() {
+        let idl_a = IDLSimple::from_iter(vec![1, 2, 3, 64, 65, 900]);
+        let idl_b = IDLSimple::from_iter(vec![2, 3, 4, 65, 1000]);
+        // Both operands are still usable afterwards.
+        assert_eq!(&idl_a & &idl_b, idl_a.clone() & idl_b.clone());
+        assert_eq!(&idl_a | &idl_b, idl_a.clone() | idl_b.clone());
+        assert_eq!((&idl_a).andnot(&idl_b), idl_a.clone().andnot(idl_b.clone()));
+        assert_eq!(&(&idl_a & &idl_b) | &idl_a, idl_a);
+
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 65, 900]);
+        let idl_b = IDLBitRange::from_iter(vec![2, 3, 4, 65, 1000]);
+        assert_eq!(&idl_a & &idl_b, idl_a.clone() & idl_b.clone());
+        assert_eq!(&idl_a | &idl_b, idl_a.clone() | idl_b.clone());
+        assert_eq!((&idl_a).andnot(&idl_b), idl_a.clone().andnot(idl_b.clone()));
+        assert_eq!(&(&idl_a & &idl_b) | &idl_a, idl_a);
+    }
 
-        let idl_result = idl_a.andnot(idl_b);
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_range_density() {
+        let idl_a = IDLBitRange::new();
+        assert_eq!(idl_a.count_ranges(), 0);
+        assert_eq!(idl_a.density(), 0.0);
+
+        let idl_a = IDLBitRange::from_iter(0..6400);
+        assert_eq!(idl_a.count_ranges(), 100);
+        assert_eq!(idl_a.density(), 1.0);
+
+        let idl_a = IDLBitRange::from_iter((0..100).map(|i| i * 64 + 7));
+        assert_eq!(idl_a.count_ranges(), 100);
+        assert_eq!(idl_a.density(), 1.0 / 64.0);
+
+        let idl_a = IDLBitRange::from_iter(vec![0, 1, 64]);
+        assert_eq!(idl_a.count_ranges(), 2);
+        assert_eq!(idl_a.density(), 3.0 / 128.0);
     }
 
     #[test]
-    fn test_range_not_1() {
-        let idl_a = IDLBitRange::from_iter(vec![1,2,3,4,5,6]);
-        let idl_b = IDLBitRange::from_iter(vec![3,4]);
-        let idl_expect = IDLBitRange::from_iter(vec![1,2,5,6]);
+    fn test_range_mem_size() {
+        let mut idl_a = IDLBitRange::new();
+        idl_a.shrink_to_fit();
+        let base = idl_a.mem_size();
+        assert_eq!(base, ::std::mem::size_of::<IDLBitRange>());
+        idl_a.push_id(1);
+        assert!(idl_a.mem_size() > base);
+        let before = idl_a.mem_size();
+        // Same range, no reallocation.
+        idl_a.push_id(2);
+        assert_eq!(idl_a.mem_size(), before);
+        idl_a.shrink_to_fit();
+        let before = idl_a.mem_size();
+        idl_a.push_id(64);
+        assert!(idl_a.mem_size() > before);
+
+        // Scattered ids favour IDLSimple, dense ids favour IDLBitRange.
+        let scattered: Vec<u64> = (0..100).map(|i| i * 1000).collect();
+        let mut simple = IDLSimple::from_iter(scattered.clone());
+        let mut range = IDLBitRange::from_iter(scattered);
+        simple.0.shrink_to_fit();
+        range.shrink_to_fit();
+        assert!(simple.mem_size() < range.mem_size());
+
+        let mut simple = IDLSimple::from_iter(0..1000);
+        let mut range = IDLBitRange::from_iter(0..1000);
+        simple.0.shrink_to_fit();
+        range.shrink_to_fit();
+        assert!(range.mem_size() < simple.mem_size());
+    }
 
-        let idl_result = idl_a.andnot(idl_b);
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_range_from_sorted() {
+        let idl_a = IDLBitRange::from_sorted_unchecked(vec![1, 2, 3, 64, 128]);
+        assert_eq!(idl_a, IDLBitRange::from_iter(vec![1, 2, 3, 64, 128]));
+        assert_eq!(idl_a.len(), 5);
+
+        let idl_a = IDLBitRange::try_from_iter(1..1024).unwrap();
+        assert_eq!(idl_a, IDLBitRange::from_iter(1..1024));
+
+        assert_eq!(IDLBitRange::try_from_iter(vec![1, 3, 2]), Err(IDLError::Unsorted));
+        assert_eq!(IDLBitRange::try_from_iter(vec![1, 64, 3]), Err(IDLError::Unsorted));
+        assert_eq!(IDLBitRange::try_from_iter(vec![1, 2, 2]), Err(IDLError::DuplicateId));
+        assert_eq!(format!("{}", IDLError::DuplicateId), "id was present more than once");
     }
 
     #[test]
-    fn test_range_not_2() {
-        let idl_a = IDLBitRange::from_iter(vec![1,2,3,4,5,6]);
-        let idl_b = IDLBitRange::from_iter(vec![10]);
-        let idl_expect = IDLBitRange::from_iter(vec![1,2,3,4,5,6]);
+    fn test_range_assign_ops() {
+        let datasets: Vec<(Vec<u64>, Vec<u64>)> = vec![
+            (vec![1, 2, 3, 4, 35, 64, 65, 128, 150], vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180]),
+            (vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900], (1..1024).collect()),
+            ((1..204800).collect(), (102400..307200).collect()),
+            (vec![1, 2, 3], vec![4, 67]),
+            (vec![1, 2, 3], Vec::new()),
+            (Vec::new(), vec![1, 2, 3]),
+        ];
+        for (a, b) in datasets {
+            let idl_a = IDLBitRange::from_iter(a);
+            let idl_b = IDLBitRange::from_iter(b);
+
+            let mut idl_result = idl_a.clone();
+            idl_result &= idl_b.clone();
+            let idl_expect = idl_a.clone() & idl_b.clone();
+            assert_eq!(idl_result, idl_expect);
+
+            let mut idl_result = idl_a.clone();
+            idl_result |= idl_b.clone();
+            let idl_expect = idl_a.clone() | idl_b.clone();
+            assert_eq!(idl_result, idl_expect);
+
+            let mut idl_result = idl_a.clone();
+            idl_result.andnot_assign(idl_b.clone());
+            let idl_expect = idl_a.andnot(idl_b);
+            assert_eq!(idl_result, idl_expect);
+        }
+    }
 
-        let idl_result = idl_a.andnot(idl_b);
+    #[test]
+    fn test_range_subset() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 128]);
+        let idl_b = IDLBitRange::from_iter(vec![1, 2, 3, 64, 128]);
+        assert!(idl_a.is_subset_of(&idl_b));
+        assert!(idl_a.is_superset_of(&idl_b));
+
+        let idl_b = IDLBitRange::from_iter(vec![2, 128]);
+        assert!(idl_b.is_subset_of(&idl_a));
+        assert!(!idl_a.is_subset_of(&idl_b));
+        assert!(idl_a.is_superset_of(&idl_b));
+        assert!(!idl_b.is_superset_of(&idl_a));
+
+        // Same ranges, but a bit is missing.
+        let idl_b = IDLBitRange::from_iter(vec![2, 4, 128]);
+        assert!(!idl_b.is_subset_of(&idl_a));
+
+        let idl_b = IDLBitRange::from_iter(vec![200, 300]);
+        assert!(!idl_b.is_subset_of(&idl_a));
+        assert!(!idl_a.is_subset_of(&idl_b));
+
+        let idl_b = IDLBitRange::new();
+        assert!(idl_b.is_subset_of(&idl_a));
+        assert!(idl_a.is_superset_of(&idl_b));
+    }
+
+    #[test]
+    fn test_range_complement_1() {
+        let idl_a = IDLBitRange::from_iter(vec![0, 2, 3, 64, 70]);
+        let idl_result = idl_a.complement_within(70);
+        let idl_expect = IDLBitRange::from_iter(
+            vec![1].into_iter().chain(4..64).chain(65..70)
+        );
         assert_eq!(idl_result, idl_expect);
+
+        let idl_result = IDLBitRange::new().complement_within(5);
+        assert_eq!(idl_result, IDLBitRange::from_iter(0..6));
+
+        let idl_result = IDLBitRange::from_iter(0..128).complement_within(127);
+        assert!(idl_result.is_empty());
     }
 
     #[test]
-    fn test_range_not_3() {
-        let idl_a = IDLBitRange::from_iter(vec![2,3,4,5,6]);
-        let idl_b = IDLBitRange::from_iter(vec![1]);
-        let idl_expect = IDLBitRange::from_iter(vec![2,3,4,5,6]);
+    fn test_range_complement_2() {
+        let idl_a = IDLBitRange::from_iter(vec![2, 3, 8, 35, 64, 128, 130, 150, 152, 180, 256, 800, 900, 5000]);
+        for max in [0, 63, 64, 127, 200, 900, 1023].iter().cloned() {
+            let idl_full = IDLBitRange::from_iter(0..(max + 1));
+            let idl_expect = idl_full.andnot(idl_a.clone());
+            assert_eq!(idl_a.complement_within(max), idl_expect);
+        }
+    }
 
-        let idl_result = idl_a.andnot(idl_b);
-        assert_eq!(idl_result, idl_expect);
+    #[test]
+    fn test_default() {
+        #[derive(Default)]
+        struct TermEntry {
+            simple: IDLSimple,
+            range: IDLBitRange,
+        }
+
+        let entry = TermEntry::default();
+        assert!(entry.simple.is_empty());
+        assert!(entry.range.is_empty());
+        assert_eq!(entry.range.len(), 0);
+        assert!(IDLBitRange::default().is_empty());
+    }
+
+    fn hash_of(idl: &IDLBitRange) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        idl.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_range_hash() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 128]);
+        let idl_b = IDLBitRange::from_iter(vec![128, 64, 3, 2, 1]);
+        assert_eq!(idl_a, idl_b);
+        assert_eq!(hash_of(&idl_a), hash_of(&idl_b));
+
+        let mut cache = HashMap::new();
+        cache.insert(idl_a, "a");
+        assert_eq!(cache.get(&idl_b), Some(&"a"));
+        assert_eq!(cache.get(&IDLBitRange::from_iter(vec![1, 2])), None);
+    }
+
+    #[test]
+    fn test_range_hash_empty_ranges() {
+        // Sets that had all their ids removed must equal and hash the same
+        // as an empty set, so no empty range may be left behind.
+        let mut idl_a = IDLBitRange::from_iter(vec![1, 64]);
+        idl_a.remove_id(1);
+        idl_a.remove_id(64);
+        let idl_b = IDLBitRange::from_iter(vec![1]) & IDLBitRange::from_iter(vec![2, 3]);
+        let idl_c = IDLBitRange::from_iter(vec![1]).andnot(IDLBitRange::from_iter(vec![1]));
+        let idl_d = IDLBitRange::from_iter(vec![1, 65]) ^ IDLBitRange::from_iter(vec![1, 65]);
+        for idl in &[idl_a, idl_b, idl_c, idl_d] {
+            assert_eq!(*idl, IDLBitRange::new());
+            assert_eq!(hash_of(idl), hash_of(&IDLBitRange::new()));
+        }
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut idl_a = IDLSimple::from_iter(vec![1, 2, 3]);
+        idl_a.extend(vec![3, 4, 100]);
+        idl_a.extend(vec![0, 50]);
+        let result: Vec<u64> = (&idl_a).into_iter().collect();
+        assert_eq!(result, vec![0, 1, 2, 3, 4, 50, 100]);
+
+        let mut idl_a = IDLBitRange::from_iter(vec![1, 2, 3]);
+        idl_a.extend(vec![3, 4, 100]);
+        idl_a.extend(vec![0, 50]);
+        idl_a.extend(1000..1100);
+        let result: Vec<u64> = (&idl_a).into_iter().collect();
+        let expect: Vec<u64> = vec![0, 1, 2, 3, 4, 50, 100].into_iter().chain(1000..1100).collect();
+        assert_eq!(result, expect);
+        assert_eq!(idl_a.len(), expect.len());
+    }
+
+    #[test]
+    fn test_convert() {
+        let idl_a = IDLSimple::from_iter(vec![1, 2, 3, 64, 128]);
+        let idl_b = IDLBitRange::from(idl_a.clone());
+        assert_eq!(idl_b, IDLBitRange::from_iter(vec![1, 2, 3, 64, 128]));
+        assert_eq!(idl_b.len(), 5);
+        let idl_c = IDLSimple::from(idl_b);
+        assert_eq!(idl_a, idl_c);
+
+        // Even an unsorted simple list produces a valid range.
+        let idl_b = IDLBitRange::from(IDLSimple(vec![128, 3, 64, 1, 3]));
+        assert_eq!(idl_b, IDLBitRange::from_iter(vec![1, 3, 64, 128]));
+        assert_eq!(IDLSimple::from(idl_b), IDLSimple::from_iter(vec![1, 3, 64, 128]));
+
+        let idl_a = IDLSimple::from_iter(1..204800);
+        let idl_c = IDLSimple::from(IDLBitRange::from(idl_a.clone()));
+        assert_eq!(idl_a, idl_c);
+    }
+
+    #[test]
+    fn test_idltype_scattered() {
+        let idl_a = IDLType::from_iter((1..300).map(|i| 64 * i + 5));
+        match idl_a {
+            IDLType::Simple(_) => {}
+            _ => panic!("scattered ids should be simple"),
+        }
+        assert_eq!(idl_a.len(), 299);
+        assert!(idl_a.contains(69));
+        assert!(!idl_a.contains(70));
+
+        let mut idl_a = IDLType::new();
+        for i in 1..300 {
+            idl_a.push_id(64 * i + 5);
+        }
+        match idl_a {
+            IDLType::Simple(_) => {}
+            _ => panic!("scattered ids should be simple"),
+        }
+    }
+
+    #[test]
+    fn test_idltype_dense() {
+        let idl_a = IDLType::from_iter(1..1024);
+        match idl_a {
+            IDLType::Compressed(_) => {}
+            _ => panic!("contiguous ids should be compressed"),
+        }
+        assert_eq!(idl_a.len(), 1023);
+
+        let mut idl_a = IDLType::new();
+        for i in 1..1024 {
+            idl_a.push_id(i);
+        }
+        match idl_a {
+            IDLType::Compressed(_) => {}
+            _ => panic!("contiguous ids should be compressed"),
+        }
+    }
+
+    #[test]
+    fn test_idltype_ops() {
+        let idl_a = IDLType::from_iter(1..1024);
+        let idl_b = IDLType::from_iter(vec![2, 64, 130, 256, 800, 900, 2000]);
+        let idl_result = idl_a.clone() & idl_b.clone();
+        // The result is sparse, so it should become simple again.
+        assert_eq!(idl_result, IDLType::Simple(
+            IDLSimple::from_iter(vec![2, 64, 130, 256, 800, 900])
+        ));
+        match idl_result {
+            IDLType::Simple(_) => {}
+            _ => panic!("sparse ids should be simple"),
+        }
+
+        let idl_result = idl_a.clone() | idl_b.clone();
+        assert_eq!(idl_result, IDLType::from_iter((1..1024).chain(vec![2000])));
+
+        let mut idl_result = idl_a.andnot(idl_b);
+        assert_eq!(idl_result.len(), 1017);
+        idl_result.optimize();
+        match idl_result {
+            IDLType::Compressed(_) => {}
+            _ => panic!("dense ids should be compressed"),
+        }
+    }
+
+    #[test]
+    fn test_idltype_eq() {
+        // The same ids held in either form are equal.
+        let ids: Vec<u64> = vec![1, 2, 3, 64, 128, 5000];
+        let idl_s = IDLType::Simple(IDLSimple::from_iter(ids.clone()));
+        let idl_c = IDLType::Compressed(IDLBitRange::from_iter(ids.clone()));
+        assert_eq!(idl_s, idl_c);
+        assert_eq!(idl_c, idl_s);
+        assert_eq!(idl_c, idl_c.clone());
+
+        // Differing ids are not, in either form.
+        let idl_d = IDLType::Compressed(IDLBitRange::from_iter(vec![1, 2, 3, 64, 128, 5001]));
+        let idl_e = IDLType::Compressed(IDLBitRange::from_iter(vec![1, 2, 3, 64, 128]));
+        assert_ne!(idl_s, idl_d);
+        assert_ne!(idl_s, idl_e);
+        assert_ne!(idl_e, idl_s);
+        assert_eq!(IDLType::Simple(IDLSimple::new()), IDLType::Compressed(IDLBitRange::new()));
+
+        // Equality doesn't change when optimize switches the form.
+        let mut idl_f = IDLType::Compressed(IDLBitRange::from_iter(vec![5, 9000]));
+        let idl_g = idl_f.clone();
+        idl_f.optimize();
+        match idl_f {
+            IDLType::Simple(_) => {}
+            _ => panic!("scattered ids should be simple"),
+        }
+        assert_eq!(idl_f, idl_g);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_simple_serde() {
+        let idl_a = IDLSimple::from_iter(vec![1, 2, 3, 64, 128]);
+        let s = serde_json::to_string(&idl_a).unwrap();
+        assert_eq!(s, "[1,2,3,64,128]");
+        let idl_b: IDLSimple = serde_json::from_str(&s).unwrap();
+        assert_eq!(idl_a, idl_b);
+        // Unsorted input is normalised.
+        let idl_c: IDLSimple = serde_json::from_str("[3,1,2,2]").unwrap();
+        assert_eq!(idl_c, IDLSimple::from_iter(vec![1, 2, 3]));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_range_serde() {
+        let idl_a = IDLBitRange::from_iter(vec![1, 2, 3, 64, 128]);
+        let s = serde_json::to_string(&idl_a).unwrap();
+        assert_eq!(s, "[[0,14],[64,1],[128,1]]");
+        let idl_b: IDLBitRange = serde_json::from_str(&s).unwrap();
+        assert_eq!(idl_a, idl_b);
+        assert_eq!(idl_b.len(), 5);
+
+        let idl_a = IDLBitRange::from_iter(1..204800);
+        let s = serde_json::to_string(&idl_a).unwrap();
+        let idl_b: IDLBitRange = serde_json::from_str(&s).unwrap();
+        assert_eq!(idl_a, idl_b);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_range_serde_invalid() {
+        // Empty mask
+        assert!(serde_json::from_str::<IDLBitRange>("[[0,14],[64,0]]").is_err());
+        // Not aligned
+        assert!(serde_json::from_str::<IDLBitRange>("[[1,14]]").is_err());
+        // Not ascending
+        assert!(serde_json::from_str::<IDLBitRange>("[[64,1],[0,14]]").is_err());
+        assert!(serde_json::from_str::<IDLBitRange>("[[64,1],[64,2]]").is_err());
     }
 
     /*
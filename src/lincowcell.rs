@@ -1,23 +1,70 @@
 
-use std::sync::{Mutex, MutexGuard, RwLock, Arc};
+use std::sync::{Mutex, MutexGuard, RwLock, Arc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::ops::Deref;
 
 #[derive(Debug)]
 pub struct LinCowCellInner<T> {
     data: T,
-    next: Mutex<Option<LinCowCellReadTxn<T>>>,
+    next: Mutex<Option<Arc<LinCowCellInner<T>>>>,
+    // Read txns open on this generation.
+    readers: AtomicUsize,
+    generation: u64,
 }
 
 impl<T> LinCowCellInner<T> {
     pub fn new(data: T) -> Self {
+        LinCowCellInner::with_generation(data, 0)
+    }
+
+    fn with_generation(data: T, generation: u64) -> Self {
         LinCowCellInner {
-            data: data,
+            data,
             next: Mutex::new(None),
+            readers: AtomicUsize::new(0),
+            generation,
+        }
+    }
+}
+
+/// A read transaction on a `LinCowCell`. This keeps its generation, and
+/// every later one, alive until it is dropped.
+#[derive(Debug)]
+pub struct LinCowCellReadTxn<T> {
+    inner: Arc<LinCowCellInner<T>>,
+}
+
+impl<T> LinCowCellReadTxn<T> {
+    fn new(inner: Arc<LinCowCellInner<T>>) -> Self {
+        inner.readers.fetch_add(1, Ordering::AcqRel);
+        LinCowCellReadTxn {
+            inner,
         }
     }
 }
 
-type LinCowCellReadTxn<T> = Arc<LinCowCellInner<T>>;
+impl<T> LinCowCellReadTxn<T> {
+    /// The generation this txn observed. This starts at 0, and each
+    /// commit increases it by one.
+    pub fn generation(&self) -> u64 {
+        self.inner.generation
+    }
+}
+
+impl<T> Drop for LinCowCellReadTxn<T> {
+    fn drop(&mut self) {
+        self.inner.readers.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl<T> Deref for LinCowCellReadTxn<T> {
+    type Target = LinCowCellInner<T>;
+
+    #[inline]
+    fn deref(&self) -> &LinCowCellInner<T> {
+        &self.inner
+    }
+}
 
 #[derive(Debug)]
 pub struct LinCowCell<T> {
@@ -26,7 +73,9 @@ pub struct LinCowCell<T> {
     // RWlock 500 MT: PT2.354443857S
     // Mutex 500 MT: PT0.006423466S
     // EBR 500 MT: PT0.003360303S
-    active: Mutex<LinCowCellReadTxn<T>>,
+    active: Mutex<Arc<LinCowCellInner<T>>>,
+    // Every generation that may still be alive, oldest first.
+    versions: Mutex<Vec<Weak<LinCowCellInner<T>>>>,
 }
 
 #[derive(Debug)]
@@ -43,19 +92,26 @@ impl<T> LinCowCell<T>
     where T: Clone
 {
     pub fn new(data: T) -> Self {
+        let inner = Arc::new(LinCowCellInner::new(data));
         LinCowCell {
             write: Mutex::new(()),
-            active: Mutex::new(
-                Arc::new(
-                    LinCowCellInner::new(data)
-                )
-            ),
+            versions: Mutex::new(vec![Arc::downgrade(&inner)]),
+            active: Mutex::new(inner),
         }
     }
 
+    /// The number of read txns open across all generations still alive.
+    pub fn live_reader_count(&self) -> usize {
+        self.versions.lock().unwrap()
+            .iter()
+            .filter_map(|v| v.upgrade())
+            .map(|v| v.readers.load(Ordering::Acquire))
+            .sum()
+    }
+
     pub fn begin_read_txn(&self) -> LinCowCellReadTxn<T> {
         let rwguard = self.active.lock().unwrap();
-        rwguard.clone()
+        LinCowCellReadTxn::new(rwguard.clone())
         /*
         LinCowCellReadTxn {
             data: rwguard.data.clone()
@@ -81,7 +137,8 @@ impl<T> LinCowCell<T>
 
     fn commit(&self, newdata: T) {
         let mut rwguard = self.active.lock().unwrap();
-        let new_inner = Arc::new(LinCowCellInner::new(newdata));
+        // We hold the active lock, so the generation can't race.
+        let new_inner = Arc::new(LinCowCellInner::with_generation(newdata, rwguard.generation + 1));
         {
             // This modiries the next pointer of the existing read txns
             let mut rwguard_inner = rwguard.next.lock().unwrap();
@@ -89,6 +146,11 @@ impl<T> LinCowCell<T>
             // add it to the last value
             *rwguard_inner = Some(new_inner.clone());
         }
+        {
+            let mut versions = self.versions.lock().unwrap();
+            versions.retain(|v| v.strong_count() > 0);
+            versions.push(Arc::downgrade(&new_inner));
+        }
         // now over-write the last value in the mutex.
         *rwguard = new_inner;
     }
@@ -120,10 +182,21 @@ impl<'a, T> LinCowCellWriteTxn<'a, T>
         &mut self.work
     }
 
+    /// A read txn on the last committed value, so the working copy can be
+    /// compared against it. As we hold the write lock, nothing else can
+    /// commit while this txn is open.
+    pub fn read(&self) -> LinCowCellReadTxn<T> {
+        self.caller.begin_read_txn()
+    }
+
     pub fn commit(self) {
         /* Write our data back to the LinCowCell */
         self.caller.commit(self.work);
     }
+
+    /// Discard the working copy and release the write lock. This is the
+    /// same as dropping the txn without calling commit.
+    pub fn abort(self) {}
 }
 
 
@@ -131,6 +204,7 @@ impl<'a, T> LinCowCellWriteTxn<'a, T>
 mod tests {
     extern crate time;
 
+    use std::sync::Mutex;
     use std::sync::atomic::{AtomicUsize, Ordering};
     use super::LinCowCell;
     use crossbeam_utils::thread::scope;
@@ -168,6 +242,99 @@ mod tests {
         assert_eq!(**cc_rotxn_a, 0);
     }
 
+    #[test]
+    fn test_abort() {
+        let data: i64 = 0;
+        let cc = LinCowCell::new(data);
+
+        let mut cc_wrtxn = cc.begin_write_txn();
+        *cc_wrtxn.get_mut() = 1;
+        cc_wrtxn.abort();
+
+        let cc_rotxn = cc.begin_read_txn();
+        assert_eq!(**cc_rotxn, 0);
+        // The write lock was released.
+        let mut cc_wrtxn = cc.begin_write_txn();
+        assert_eq!(*cc_wrtxn.get_mut(), 0);
+    }
+
+    #[test]
+    fn test_live_reader_count() {
+        let data: i64 = 0;
+        let cc = LinCowCell::new(data);
+        assert_eq!(cc.live_reader_count(), 0);
+
+        let cc_rotxn_a = cc.begin_read_txn();
+        assert_eq!(cc.live_reader_count(), 1);
+        {
+            let mut cc_wrtxn = cc.begin_write_txn();
+            *cc_wrtxn.get_mut() = 1;
+            cc_wrtxn.commit();
+        }
+        let cc_rotxn_b = cc.begin_read_txn();
+        let cc_rotxn_b2 = cc.begin_read_txn();
+        {
+            let mut cc_wrtxn = cc.begin_write_txn();
+            *cc_wrtxn.get_mut() = 2;
+            cc_wrtxn.commit();
+        }
+        let cc_rotxn_c = cc.begin_read_txn();
+        assert_eq!(cc.live_reader_count(), 4);
+
+        drop(cc_rotxn_b);
+        assert_eq!(cc.live_reader_count(), 3);
+        // A still pins every later generation.
+        drop(cc_rotxn_a);
+        assert_eq!(cc.live_reader_count(), 2);
+        drop(cc_rotxn_c);
+        assert_eq!(cc.live_reader_count(), 1);
+        assert_eq!(**cc_rotxn_b2, 1);
+        drop(cc_rotxn_b2);
+        assert_eq!(cc.live_reader_count(), 0);
+    }
+
+    #[test]
+    fn test_write_txn_read() {
+        let data: i64 = 0;
+        let cc = LinCowCell::new(data);
+
+        let mut cc_wrtxn = cc.begin_write_txn();
+        *cc_wrtxn.get_mut() = 1;
+        let cc_rotxn_a = cc_wrtxn.read();
+        assert_eq!(**cc_rotxn_a, 0);
+        assert_eq!(*cc_wrtxn.get_mut(), 1);
+        cc_wrtxn.commit();
+
+        // The snapshot taken during the write doesn't see the commit.
+        assert_eq!(**cc_rotxn_a, 0);
+        let mut cc_wrtxn = cc.begin_write_txn();
+        assert_eq!(**cc_wrtxn.read(), 1);
+        *cc_wrtxn.get_mut() = 2;
+        assert_eq!(**cc_wrtxn.read(), 1);
+    }
+
+    #[test]
+    fn test_generation() {
+        let data: i64 = 0;
+        let cc = LinCowCell::new(data);
+        let cc_rotxn_a = cc.begin_read_txn();
+        assert_eq!(cc_rotxn_a.generation(), 0);
+
+        let mut last = cc_rotxn_a.generation();
+        for i in 1..5 {
+            let mut cc_wrtxn = cc.begin_write_txn();
+            *cc_wrtxn.get_mut() = i;
+            cc_wrtxn.commit();
+            let cc_rotxn = cc.begin_read_txn();
+            assert!(cc_rotxn.generation() > last);
+            last = cc_rotxn.generation();
+        }
+        assert_eq!(last, 4);
+        // The stale txn keeps its own generation.
+        assert_eq!(cc_rotxn_a.generation(), 0);
+        assert_eq!(**cc_rotxn_a, 0);
+    }
+
     fn mt_writer(cc: &LinCowCell<i64>) {
         let mut last_value: i64 = 0;
         while last_value < 500 {
@@ -267,6 +434,46 @@ mod tests {
         assert!(GC_COUNT.load(Ordering::Acquire) >= 50);
     }
 
+    static DROP_ORDER: Mutex<Vec<i64>> = Mutex::new(Vec::new());
+
+    #[derive(Debug, Clone)]
+    struct TestDropOrder {
+        data: i64
+    }
+
+    impl Drop for TestDropOrder {
+        fn drop(&mut self) {
+            DROP_ORDER.lock().unwrap().push(self.data);
+        }
+    }
+
+    /*
+     * Each generation links to the next, so when the oldest reader goes
+     * the generations it pinned are dropped oldest first.
+     */
+    #[test]
+    fn test_drop_order_chained() {
+        let cc = LinCowCell::new(TestDropOrder{data: 0});
+        let mut readers = Vec::new();
+        for i in 1..4 {
+            readers.push(cc.begin_read_txn());
+            let mut cc_wrtxn = cc.begin_write_txn();
+            cc_wrtxn.get_mut().data = i;
+            cc_wrtxn.commit();
+        }
+        assert!(DROP_ORDER.lock().unwrap().is_empty());
+
+        // Dropping the newer readers first frees nothing.
+        let cc_rotxn_a = readers.remove(0);
+        drop(readers);
+        assert!(DROP_ORDER.lock().unwrap().is_empty());
+
+        drop(cc_rotxn_a);
+        assert_eq!(*DROP_ORDER.lock().unwrap(), vec![0, 1, 2]);
+        drop(cc);
+        assert_eq!(*DROP_ORDER.lock().unwrap(), vec![0, 1, 2, 3]);
+    }
+
     /*
      * This tests an important property of the lincowcell over the cow cell
      * that read txns are dropped *in order*.